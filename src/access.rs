@@ -0,0 +1,334 @@
+use crate::errors::{DogError, DogResult};
+use crate::headers::Headers;
+use crate::logger::Logger;
+use std::net::IpAddr;
+use toml::Value;
+
+/// Normalizes IPv4-mapped IPv6 addresses (`::ffff:1.2.3.4`) to plain IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// An IPv4 / IPv6 network in CIDR notation, a bare address is a single host network.
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let parsed = addr.trim().parse::<IpAddr>().ok()?;
+        let max = if parsed.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|t| *t <= max)?,
+            None => max,
+        };
+        let addr = canonical(parsed);
+        // Mapped networks keep the prefix of their IPv4 part, the first 96 bits are fixed
+        let prefix = if addr.is_ipv4() && parsed.is_ipv6() {
+            prefix.checked_sub(96)?
+        } else {
+            prefix
+        };
+        Some(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a list of CIDRs, e.g. for `trusted_proxies`.
+pub fn parse_cidrs(logger: &Logger, key: &str, values: &[String]) -> DogResult<Vec<Cidr>> {
+    values
+        .iter()
+        .map(|value| {
+            Cidr::parse(value).ok_or_else(|| {
+                DogError::new(
+                    logger,
+                    "usr-cfgensure-cfgld".to_string(),
+                    format!("Ill formatted network '{}' in key '{}'", value, key),
+                )
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+struct AccessRule {
+    allow: bool,
+    /// `None` matches every address (`all`)
+    network: Option<Cidr>,
+}
+
+/// Ordered list of `allow` / `deny` rules, the first matching rule decides.
+/// Addresses matching no rule are allowed.
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    rules: Vec<AccessRule>,
+}
+
+impl AccessList {
+    /// Parses rules like `["allow 10.0.0.0/8", "allow ::1", "deny all"]`.
+    pub fn new(logger: &Logger, value: &Value) -> DogResult<Self> {
+        let ill_formatted = |rule: &str| {
+            DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                format!("Ill formatted access rule '{}'", rule),
+            )
+        };
+        let values = value.as_array().ok_or_else(|| ill_formatted("access"))?;
+        let mut rules = vec![];
+        for value in values {
+            let rule = value
+                .as_str()
+                .ok_or_else(|| ill_formatted(&value.to_string()))?;
+            let (action, network) = rule
+                .trim()
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| ill_formatted(rule))?;
+            let allow = match action {
+                "allow" => true,
+                "deny" => false,
+                _ => return Err(ill_formatted(rule)),
+            };
+            let network = match network.trim() {
+                "all" => None,
+                network => Some(Cidr::parse(network).ok_or_else(|| ill_formatted(rule))?),
+            };
+            rules.push(AccessRule { allow, network });
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.network.as_ref().is_none_or(|t| t.contains(ip)))
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+/// Resolves the real client address of a request.
+/// If the peer is a trusted proxy, `X-Forwarded-For` is walked from the right,
+/// skipping trusted proxies, until the first untrusted address.
+pub fn resolve_client_ip(peer: IpAddr, headers: &Headers, trusted_proxies: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|t| t.contains(ip));
+    let mut client = canonical(peer);
+    if !is_trusted(client) {
+        return client;
+    }
+    let forwarded = headers.get_all("X-Forwarded-For").join(",");
+    for hop in forwarded.rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = canonical(ip);
+                if !is_trusted(client) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn access_list(rules: &[&str]) -> DogResult<AccessList> {
+        let logger = Logger::new(false, None).unwrap();
+        let value = Value::Array(rules.iter().map(|t| Value::from(*t)).collect());
+        AccessList::new(&logger, &value)
+    }
+
+    fn forwarded(values: &[&str]) -> Headers {
+        let mut headers = Headers::new();
+        for value in values {
+            headers.append("X-Forwarded-For", value).unwrap();
+        }
+        headers
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert!(Cidr::parse("10.0.0.0/8").is_some());
+        assert!(Cidr::parse(" 10.0.0.0 / 8 ").is_some());
+        assert!(Cidr::parse("10.0.0.0/0").is_some());
+        assert!(Cidr::parse("2001:db8::/32").is_some());
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+        assert!(Cidr::parse("2001:db8::/129").is_none());
+        assert!(Cidr::parse("10.0.0.0/").is_none());
+        assert!(Cidr::parse("10.0.0.0/-1").is_none());
+        assert!(Cidr::parse("10.0.0.256").is_none());
+        assert!(Cidr::parse("localhost").is_none());
+        assert!(Cidr::parse("").is_none());
+    }
+
+    #[test]
+    fn cidr_contains_v4() {
+        let net = Cidr::parse("192.168.1.0/24").unwrap();
+        assert!(net.contains(ip("192.168.1.0")));
+        assert!(net.contains(ip("192.168.1.255")));
+        assert!(!net.contains(ip("192.168.2.0")));
+        assert!(!net.contains(ip("192.168.0.255")));
+        assert!(!net.contains(ip("::1")));
+
+        let host = Cidr::parse("10.1.2.3").unwrap();
+        assert!(host.contains(ip("10.1.2.3")));
+        assert!(!host.contains(ip("10.1.2.4")));
+
+        let all = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(all.contains(ip("255.255.255.255")));
+        assert!(!all.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn cidr_contains_v6() {
+        let net = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(net.contains(ip("2001:db8::1")));
+        assert!(net.contains(ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!net.contains(ip("2001:db9::")));
+        assert!(!net.contains(ip("32.1.13.184")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_networks() {
+        let net = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains(ip("::ffff:10.20.30.40")));
+        assert!(!net.contains(ip("::ffff:11.0.0.1")));
+        // Mapped networks are normalized as well
+        assert!(Cidr::parse("::ffff:10.0.0.1")
+            .unwrap()
+            .contains(ip("10.0.0.1")));
+        let mapped = Cidr::parse("::ffff:0:0/96").unwrap();
+        assert!(mapped.contains(ip("10.0.0.1")));
+        assert!(mapped.contains(ip("::ffff:192.0.2.1")));
+        assert!(!mapped.contains(ip("2001:db8::1")));
+        let mapped = Cidr::parse("::ffff:192.168.0.0/112").unwrap();
+        assert!(mapped.contains(ip("192.168.1.1")));
+        assert!(!mapped.contains(ip("192.169.0.1")));
+        assert!(Cidr::parse("::ffff:10.0.0.0/95").is_none());
+    }
+
+    #[test]
+    fn access_list_first_match_wins() {
+        let list = access_list(&["deny 10.0.0.1", "allow 10.0.0.0/8", "deny all"]).unwrap();
+        assert!(!list.is_allowed(ip("10.0.0.1")));
+        assert!(list.is_allowed(ip("10.0.0.2")));
+        assert!(!list.is_allowed(ip("11.0.0.1")));
+        assert!(!list.is_allowed(ip("::1")));
+    }
+
+    #[test]
+    fn access_list_allows_unmatched() {
+        let list = access_list(&["deny 192.168.0.0/16"]).unwrap();
+        assert!(list.is_allowed(ip("10.0.0.1")));
+        assert!(!list.is_allowed(ip("192.168.3.4")));
+        assert!(access_list(&[]).unwrap().is_allowed(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn access_list_rejects_malformed_rules() {
+        assert!(access_list(&["permit 10.0.0.0/8"]).is_err());
+        assert!(access_list(&["allow"]).is_err());
+        assert!(access_list(&["allow 10.0.0.0/40"]).is_err());
+        assert!(access_list(&["allow everyone"]).is_err());
+        assert!(access_list(&["Allow all"]).is_err());
+
+        let logger = Logger::new(false, None).unwrap();
+        assert!(AccessList::new(&logger, &Value::from("allow all")).is_err());
+        assert!(AccessList::new(&logger, &Value::Array(vec![Value::from(1)])).is_err());
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let trusted = [Cidr::parse("10.0.0.0/8").unwrap()];
+        let headers = forwarded(&["1.2.3.4"]);
+        assert_eq!(
+            resolve_client_ip(ip("5.6.7.8"), &headers, &trusted),
+            ip("5.6.7.8")
+        );
+        assert_eq!(
+            resolve_client_ip(ip("5.6.7.8"), &headers, &[]),
+            ip("5.6.7.8")
+        );
+    }
+
+    #[test]
+    fn forwarded_for_is_walked_from_the_right() {
+        let trusted = [Cidr::parse("10.0.0.0/8").unwrap()];
+        // The client may put anything on the left, only hops added by trusted proxies count
+        let headers = forwarded(&["6.6.6.6, 1.2.3.4, 10.0.0.2"]);
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("1.2.3.4")
+        );
+        // Several headers are one list
+        let headers = forwarded(&["6.6.6.6, 1.2.3.4", "10.0.0.2"]);
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.1"), &Headers::new(), &trusted),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_for_stops_at_malformed_hops() {
+        let trusted = [Cidr::parse("10.0.0.0/8").unwrap()];
+        let headers = forwarded(&["1.2.3.4, garbage, 10.0.0.3"]);
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("10.0.0.3")
+        );
+        let headers = forwarded(&["1.2.3.4, "]);
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("10.0.0.1")
+        );
+        // Only trusted hops leave the leftmost one
+        let headers = forwarded(&["10.0.0.9, 10.0.0.8"]);
+        assert_eq!(
+            resolve_client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("10.0.0.9")
+        );
+    }
+
+    #[test]
+    fn forwarded_for_normalizes_mapped_addresses() {
+        let trusted = [Cidr::parse("10.0.0.0/8").unwrap()];
+        let headers = forwarded(&["::ffff:1.2.3.4"]);
+        assert_eq!(
+            resolve_client_ip(ip("::ffff:10.0.0.1"), &headers, &trusted),
+            ip("1.2.3.4")
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use crate::errors::{HttpCode, NetError, NetResult};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Caps the number of open connections, globally and per client address.
+/// Shared between the acceptor and all workers.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimiter {
+    max: usize,
+    max_per_ip: Option<usize>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+/// Held while a connection is open, releases its slot when dropped.
+pub struct ConnectionGuard {
+    ip: Option<IpAddr>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max: usize, max_per_ip: Option<usize>) -> Self {
+        Self {
+            max,
+            max_per_ip,
+            counts: Arc::new(Mutex::new(Counts::default())),
+        }
+    }
+
+    /// Takes a slot for a new connection, answering with 503 once all slots are taken
+    /// and with 429 if the client already has too many connections open.
+    pub fn acquire(&self, ip: Option<IpAddr>) -> NetResult<ConnectionGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.total >= self.max {
+            return Err(NetError::new(
+                HttpCode::SERVICE_UNAVAILABLE,
+                Some("Too many connections".to_string()),
+            )
+            .with_header("Retry-After", "1"));
+        }
+        if let Some(ip) = ip {
+            // Rejected addresses must not leave an entry behind, which would never be removed
+            let open = counts.per_ip.get(&ip).copied().unwrap_or(0);
+            if self.max_per_ip.is_some_and(|t| open >= t) {
+                return Err(NetError::new(
+                    HttpCode::TOO_MANY_REQUESTS,
+                    Some("Too many connections from this address".to_string()),
+                )
+                .with_header("Retry-After", "1"));
+            }
+            counts.per_ip.insert(ip, open + 1);
+        }
+        counts.total += 1;
+
+        Ok(ConnectionGuard {
+            ip,
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(open) = counts.per_ip.get_mut(&ip) {
+                *open -= 1;
+                if *open == 0 {
+                    counts.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    #[test]
+    fn limits_total_and_per_address() {
+        let limiter = ConnectionLimiter::new(3, Some(2));
+        let first = limiter.acquire(ip("10.0.0.1")).unwrap();
+        let _second = limiter.acquire(ip("10.0.0.1")).unwrap();
+        let error = limiter.acquire(ip("10.0.0.1")).err().unwrap();
+        assert_eq!(error.erc, HttpCode::TOO_MANY_REQUESTS);
+        let _third = limiter.acquire(ip("10.0.0.2")).unwrap();
+        let error = limiter.acquire(None).err().unwrap();
+        assert_eq!(error.erc, HttpCode::SERVICE_UNAVAILABLE);
+
+        // Closed connections free their slots
+        drop(first);
+        assert!(limiter.acquire(ip("10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn rejected_addresses_are_not_tracked() {
+        let limiter = ConnectionLimiter::new(1, Some(0));
+        let error = limiter.acquire(ip("10.0.0.1")).err().unwrap();
+        assert_eq!(error.erc, HttpCode::TOO_MANY_REQUESTS);
+        assert!(limiter.counts.lock().unwrap().per_ip.is_empty());
+
+        let limiter = ConnectionLimiter::new(1, None);
+        let guard = limiter.acquire(ip("10.0.0.1")).unwrap();
+        assert!(limiter.acquire(ip("10.0.0.2")).is_err());
+        assert_eq!(limiter.counts.lock().unwrap().per_ip.len(), 1);
+        drop(guard);
+        let counts = limiter.counts.lock().unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.per_ip.is_empty());
+    }
+}
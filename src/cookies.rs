@@ -0,0 +1,303 @@
+use crate::response::HTTP_DATE_FORMAT_STR;
+use chrono::DateTime;
+
+/// Cookie names are tokens (RFC 9110), like header names.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|t| t.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&t))
+}
+
+/// Cookie values are `cookie-octet`s (RFC 6265), optionally in double quotes.
+pub fn is_valid_value(value: &str) -> bool {
+    let value = value
+        .strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .unwrap_or(value);
+    value
+        .bytes()
+        .all(|t| matches!(t, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E))
+}
+
+/// Attribute values may not contain control characters or `;`.
+fn is_valid_attribute(value: &str) -> bool {
+    !value.is_empty() && !value.chars().any(|t| t.is_control() || t == ';')
+}
+
+/// Parses a `Cookie` header into name / value pairs, skipping malformed ones.
+/// Browsers send the most specific cookie first if names repeat, so callers should keep the first.
+pub fn parse(header: &str) -> Vec<(String, String)> {
+    header
+        .split(';')
+        .filter_map(|t| t.split_once('='))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .filter(|(name, value)| is_valid_name(name) && is_valid_value(value))
+        .map(|(name, value)| {
+            let value = value
+                .strip_prefix('"')
+                .and_then(|t| t.strip_suffix('"'))
+                .unwrap_or(value);
+            (name.to_string(), value.to_string())
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Some(SameSite::Strict),
+            "lax" => Some(SameSite::Lax),
+            "none" => Some(SameSite::None),
+            _ => None,
+        }
+    }
+}
+
+/// A `Set-Cookie` header value with its attributes.
+#[derive(Clone, Debug, Default)]
+pub struct SetCookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<i64>,
+    /// Unix time
+    pub expires: Option<i64>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// A cookie telling the browser to drop `name`, it has to match the path and domain it was set with.
+    pub fn removal(name: &str) -> Self {
+        Self {
+            max_age: Some(0),
+            expires: Some(0),
+            ..Self::new(name, "")
+        }
+    }
+
+    /// Builds the header value, failing on names, values or attributes which would break it.
+    pub fn build(&self) -> Result<String, String> {
+        if !is_valid_name(&self.name) {
+            return Err(format!("Invalid cookie name '{}'", self.name));
+        }
+        if !is_valid_value(&self.value) {
+            return Err(format!("Invalid value for cookie '{}'", self.name));
+        }
+        let mut cookie = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            if !is_valid_attribute(path) || !path.starts_with('/') {
+                return Err(format!("Invalid path for cookie '{}'", self.name));
+            }
+            cookie += format!("; Path={}", path).as_str();
+        }
+        if let Some(domain) = &self.domain {
+            let valid = is_valid_attribute(domain)
+                && domain
+                    .trim_start_matches('.')
+                    .bytes()
+                    .all(|t| t.is_ascii_alphanumeric() || t == b'-' || t == b'.');
+            if !valid {
+                return Err(format!("Invalid domain for cookie '{}'", self.name));
+            }
+            cookie += format!("; Domain={}", domain).as_str();
+        }
+        if let Some(max_age) = self.max_age {
+            cookie += format!("; Max-Age={}", max_age.max(0)).as_str();
+        }
+        if let Some(expires) = self.expires {
+            let expires = DateTime::from_timestamp(expires, 0)
+                .ok_or_else(|| format!("Invalid expiry for cookie '{}'", self.name))?;
+            cookie += format!("; Expires={}", expires.format(HTTP_DATE_FORMAT_STR)).as_str();
+        }
+        // Browsers reject SameSite=None without Secure
+        if self.same_site == Some(SameSite::None) && !self.secure {
+            return Err(format!(
+                "Cookie '{}' with SameSite=None has to be Secure",
+                self.name
+            ));
+        }
+        if self.secure {
+            cookie += "; Secure";
+        }
+        if self.http_only {
+            cookie += "; HttpOnly";
+        }
+        if let Some(same_site) = self.same_site {
+            cookie += format!("; SameSite={:?}", same_site).as_str();
+        }
+        Ok(cookie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(is_valid_name("session_id"));
+        assert!(is_valid_name("__Host-id"));
+        assert!(is_valid_name("a!#$%&'*+-.^_`|~1"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("a b"));
+        assert!(!is_valid_name("a=b"));
+        assert!(!is_valid_name("a;b"));
+        assert!(!is_valid_name("a,b"));
+        assert!(!is_valid_name("\"a\""));
+        assert!(!is_valid_name("a\tb"));
+        assert!(!is_valid_name("ä"));
+    }
+
+    #[test]
+    fn values() {
+        assert!(is_valid_value(""));
+        assert!(is_valid_value("abc123"));
+        assert!(is_valid_value("a=b/c:d?e"));
+        assert!(is_valid_value("\"quoted\""));
+        assert!(is_valid_value("\"\""));
+        assert!(!is_valid_value("a b"));
+        assert!(!is_valid_value("a;b"));
+        assert!(!is_valid_value("a,b"));
+        assert!(!is_valid_value("a\\b"));
+        assert!(!is_valid_value("a\"b"));
+        assert!(!is_valid_value("\"unterminated"));
+        assert!(!is_valid_value("a\r\nSet-Cookie: x=y"));
+        assert!(!is_valid_value("\u{7f}"));
+        assert!(!is_valid_value("ä"));
+    }
+
+    #[test]
+    fn parse_header() {
+        assert_eq!(
+            parse("a=1; b=\"two\";c=; d=x=y"),
+            [("a", "1"), ("b", "two"), ("c", ""), ("d", "x=y")]
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+        // Repeated names are kept in order, callers keep the first
+        assert_eq!(
+            parse("id=new; id=old"),
+            [("id", "new"), ("id", "old")]
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_skips_malformed_pairs() {
+        assert_eq!(
+            parse("novalue; =empty; a b=1; c=a b; d=\"x; ok=1"),
+            vec![("ok".to_string(), "1".to_string())]
+        );
+        assert!(parse("").is_empty());
+        assert!(parse(";;;").is_empty());
+    }
+
+    #[test]
+    fn build_minimal() {
+        assert_eq!(SetCookie::new("a", "1").build().unwrap(), "a=1");
+    }
+
+    #[test]
+    fn build_all_attributes() {
+        let cookie = SetCookie {
+            path: Some("/app".to_string()),
+            domain: Some(".example.com".to_string()),
+            max_age: Some(3600),
+            expires: Some(0),
+            secure: true,
+            http_only: true,
+            same_site: Some(SameSite::Strict),
+            ..SetCookie::new("id", "abc")
+        };
+        assert_eq!(
+            cookie.build().unwrap(),
+            "id=abc; Path=/app; Domain=.example.com; Max-Age=3600; \
+             Expires=Thu, 01 Jan 1970 00:00:00 GMT; Secure; HttpOnly; SameSite=Strict"
+        );
+    }
+
+    #[test]
+    fn build_removal() {
+        assert_eq!(
+            SetCookie::removal("id").build().unwrap(),
+            "id=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+        let negative = SetCookie {
+            max_age: Some(-5),
+            ..SetCookie::new("id", "x")
+        };
+        assert_eq!(negative.build().unwrap(), "id=x; Max-Age=0");
+    }
+
+    #[test]
+    fn build_rejects_header_injection() {
+        assert!(SetCookie::new("a b", "1").build().is_err());
+        assert!(SetCookie::new("a", "1; Domain=evil.com").build().is_err());
+        assert!(SetCookie::new("a", "1\r\nX-Evil: 1").build().is_err());
+        let path = SetCookie {
+            path: Some("/; Secure".to_string()),
+            ..SetCookie::new("a", "1")
+        };
+        assert!(path.build().is_err());
+        let relative = SetCookie {
+            path: Some("app".to_string()),
+            ..SetCookie::new("a", "1")
+        };
+        assert!(relative.build().is_err());
+        let domain = SetCookie {
+            domain: Some("example.com\n".to_string()),
+            ..SetCookie::new("a", "1")
+        };
+        assert!(domain.build().is_err());
+        let domain = SetCookie {
+            domain: Some("exa mple.com".to_string()),
+            ..SetCookie::new("a", "1")
+        };
+        assert!(domain.build().is_err());
+        let expires = SetCookie {
+            expires: Some(i64::MAX),
+            ..SetCookie::new("a", "1")
+        };
+        assert!(expires.build().is_err());
+    }
+
+    #[test]
+    fn same_site_none_needs_secure() {
+        let cookie = SetCookie {
+            same_site: Some(SameSite::None),
+            ..SetCookie::new("a", "1")
+        };
+        assert!(cookie.build().is_err());
+        let cookie = SetCookie {
+            secure: true,
+            ..cookie
+        };
+        assert_eq!(cookie.build().unwrap(), "a=1; Secure; SameSite=None");
+    }
+
+    #[test]
+    fn same_site_from_str() {
+        assert_eq!(SameSite::from_str("strict"), Some(SameSite::Strict));
+        assert_eq!(SameSite::from_str("LAX"), Some(SameSite::Lax));
+        assert_eq!(SameSite::from_str("None"), Some(SameSite::None));
+        assert_eq!(SameSite::from_str("sometimes"), None);
+    }
+}
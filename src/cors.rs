@@ -0,0 +1,367 @@
+use crate::errors::{DogError, DogResult};
+use crate::headers::Headers;
+use crate::logger::Logger;
+use toml::{Table, Value};
+
+#[derive(Clone, Debug)]
+enum OriginPattern {
+    Any,
+    Exact(String),
+    /// `https://*.example.com`, stored as prefix and suffix around the `*`
+    Wildcard(String, String),
+}
+
+impl OriginPattern {
+    fn parse(s: &str) -> Self {
+        match s.split_once('*') {
+            None => OriginPattern::Exact(s.to_lowercase()),
+            Some(("", "")) => OriginPattern::Any,
+            Some((prefix, suffix)) => {
+                OriginPattern::Wildcard(prefix.to_lowercase(), suffix.to_lowercase())
+            }
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => *exact == origin,
+            OriginPattern::Wildcard(prefix, suffix) => {
+                origin.len() > prefix.len() + suffix.len()
+                    && origin.starts_with(prefix.as_str())
+                    && origin.ends_with(suffix.as_str())
+            }
+        }
+    }
+}
+
+/// Cross-origin resource sharing settings of a route.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    origins: Vec<OriginPattern>,
+    methods: Option<Vec<String>>,
+    headers: Option<Vec<String>>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl Cors {
+    /// Loads a route's `cors = { origins = [...], ... }` setting.
+    pub fn new(logger: &Logger, t: &Table) -> DogResult<Self> {
+        let ill_formatted = |key: &str| {
+            DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                format!("Ill formatted key 'cors.{}'", key),
+            )
+        };
+        let get_list = |key: &str| -> DogResult<Option<Vec<String>>> {
+            match t.get(key) {
+                None => Ok(None),
+                Some(Value::String(value)) => Ok(Some(vec![value.clone()])),
+                Some(Value::Array(values)) => values
+                    .iter()
+                    .map(|t1| t1.as_str().map(|t2| t2.to_string()))
+                    .collect::<Option<Vec<String>>>()
+                    .map(Some)
+                    .ok_or_else(|| ill_formatted(key)),
+                Some(_) => Err(ill_formatted(key)),
+            }
+        };
+        // Values end up in response headers
+        let validate = |key: &str, values: &Option<Vec<String>>| -> DogResult<()> {
+            for value in values.iter().flatten() {
+                if value.is_empty() || Headers::validate(key, value).is_err() || value.contains(',')
+                {
+                    return Err(ill_formatted(key));
+                }
+            }
+            Ok(())
+        };
+
+        let origins = get_list("origins")?.ok_or_else(|| ill_formatted("origins"))?;
+        let methods = get_list("methods")?.map(|t1| {
+            t1.iter()
+                .map(|t2| t2.to_uppercase())
+                .collect::<Vec<String>>()
+        });
+        let headers = get_list("headers")?;
+        let expose_headers = get_list("expose_headers")?;
+        validate("methods", &methods)?;
+        validate("headers", &headers)?;
+        validate("expose_headers", &expose_headers)?;
+        let credentials = match t.get("credentials") {
+            None => false,
+            Some(value) => value
+                .as_bool()
+                .ok_or_else(|| ill_formatted("credentials"))?,
+        };
+        let max_age = match t.get("max_age") {
+            None => None,
+            Some(value) => Some(
+                value
+                    .as_integer()
+                    .and_then(|t1| u64::try_from(t1).ok())
+                    .ok_or_else(|| ill_formatted("max_age"))?,
+            ),
+        };
+
+        Ok(Self {
+            origins: origins.iter().map(|t1| OriginPattern::parse(t1)).collect(),
+            methods,
+            headers,
+            expose_headers: expose_headers.unwrap_or_default(),
+            credentials,
+            max_age,
+        })
+    }
+
+    fn allowed_origin<'a>(&self, request_headers: &'a Headers) -> Option<&'a str> {
+        request_headers
+            .get("Origin")
+            .filter(|origin| self.origins.iter().any(|t| t.matches(origin)))
+    }
+
+    fn allow_origin_headers(&self, origin: &str) -> Headers {
+        let mut headers = Headers::new();
+        let any = self.origins.iter().any(|t| matches!(t, OriginPattern::Any));
+        // Credentialed requests need the exact origin instead of '*'
+        if any && !self.credentials {
+            let _ = headers.append("Access-Control-Allow-Origin", "*");
+        } else {
+            let _ = headers.append("Access-Control-Allow-Origin", origin);
+            let _ = headers.append("Vary", "Origin");
+        }
+        if self.credentials {
+            let _ = headers.append("Access-Control-Allow-Credentials", "true");
+        }
+        headers
+    }
+
+    /// Headers for an actual (non-preflight) response.
+    pub fn response_headers(&self, request_headers: &Headers) -> Headers {
+        let origin = match self.allowed_origin(request_headers) {
+            Some(origin) => origin,
+            None => return Headers::new(),
+        };
+        let mut headers = self.allow_origin_headers(origin);
+        if !self.expose_headers.is_empty() {
+            let _ = headers.append(
+                "Access-Control-Expose-Headers",
+                &self.expose_headers.join(", "),
+            );
+        }
+        headers
+    }
+
+    /// Answers a preflight request, `route_methods` are used if no `methods` are configured.
+    /// Returns `None` if the origin, method or headers are not allowed.
+    pub fn preflight_headers(
+        &self,
+        request_headers: &Headers,
+        route_methods: &[String],
+    ) -> Option<Headers> {
+        let origin = self.allowed_origin(request_headers)?;
+        let methods = self.methods.as_deref().unwrap_or(route_methods);
+        let method = request_headers.get("Access-Control-Request-Method")?;
+        if !methods.iter().any(|t| t == method) {
+            return None;
+        }
+        let requested_headers: Vec<&str> = request_headers
+            .get("Access-Control-Request-Headers")
+            .map(|t| {
+                t.split(',')
+                    .map(|t1| t1.trim())
+                    .filter(|t1| !t1.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let allowed_headers = match &self.headers {
+            Some(allowed) => {
+                let all_allowed = requested_headers.iter().all(|requested| {
+                    allowed
+                        .iter()
+                        .any(|t| t == "*" || t.eq_ignore_ascii_case(requested))
+                });
+                if !all_allowed {
+                    return None;
+                }
+                allowed.join(", ")
+            }
+            // Without a configured list, the requested headers are allowed
+            None => requested_headers.join(", "),
+        };
+
+        let mut headers = self.allow_origin_headers(origin);
+        let _ = headers.append("Access-Control-Allow-Methods", &methods.join(", "));
+        if !allowed_headers.is_empty() {
+            let _ = headers.append("Access-Control-Allow-Headers", &allowed_headers);
+        }
+        if let Some(max_age) = self.max_age {
+            let _ = headers.append("Access-Control-Max-Age", &max_age.to_string());
+        }
+        Some(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(settings: &str) -> DogResult<Cors> {
+        let t: Table = settings.parse().unwrap();
+        Cors::new(&Logger::new(false, None).unwrap(), &t)
+    }
+
+    fn origin(value: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.append("Origin", value).unwrap();
+        headers
+    }
+
+    fn preflight(value: &str, method: &str, requested: Option<&str>) -> Headers {
+        let mut headers = origin(value);
+        headers
+            .append("Access-Control-Request-Method", method)
+            .unwrap();
+        if let Some(requested) = requested {
+            headers
+                .append("Access-Control-Request-Headers", requested)
+                .unwrap();
+        }
+        headers
+    }
+
+    #[test]
+    fn wildcard_origins() {
+        let pattern = OriginPattern::parse("https://*.example.com");
+        assert!(pattern.matches("https://api.example.com"));
+        assert!(pattern.matches("HTTPS://A.B.Example.COM"));
+        assert!(!pattern.matches("https://.example.com"));
+        assert!(!pattern.matches("https://example.com"));
+        assert!(!pattern.matches("http://api.example.com"));
+        assert!(!pattern.matches("https://api.example.com.evil.net"));
+        assert!(!pattern.matches("https://evilexample.com"));
+
+        let exact = OriginPattern::parse("https://Example.com");
+        assert!(exact.matches("https://example.com"));
+        assert!(!exact.matches("https://example.com:8443"));
+        assert!(OriginPattern::parse("*").matches("null"));
+    }
+
+    #[test]
+    fn any_origin_without_credentials() {
+        let cors = cors("origins = \"*\"").unwrap();
+        let headers = cors.response_headers(&origin("https://a.test"));
+        assert_eq!(headers.get("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(headers.get("Vary"), None);
+        assert_eq!(headers.get("Access-Control-Allow-Credentials"), None);
+        assert_eq!(cors.response_headers(&Headers::new()).iter().count(), 0);
+    }
+
+    #[test]
+    fn credentials_echo_the_origin() {
+        let any = cors("origins = \"*\"\ncredentials = true").unwrap();
+        let headers = any.response_headers(&origin("https://a.test"));
+        assert_eq!(
+            headers.get("Access-Control-Allow-Origin"),
+            Some("https://a.test")
+        );
+        assert_eq!(headers.get("Vary"), Some("Origin"));
+        assert_eq!(
+            headers.get("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+
+        let wildcard =
+            cors("origins = [\"https://*.a.test\"]\nexpose_headers = [\"X-Id\", \"X-Total\"]")
+                .unwrap();
+        let headers = wildcard.response_headers(&origin("https://www.a.test"));
+        assert_eq!(headers.get("Vary"), Some("Origin"));
+        assert_eq!(
+            headers.get("Access-Control-Expose-Headers"),
+            Some("X-Id, X-Total")
+        );
+        assert_eq!(
+            wildcard
+                .response_headers(&origin("https://b.test"))
+                .iter()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn preflight_checks_method_and_headers() {
+        let cors = cors(
+            "origins = [\"https://a.test\"]\nmethods = [\"get\", \"put\"]\nheaders = [\"Content-Type\"]\nmax_age = 600",
+        )
+        .unwrap();
+        let routes = ["GET".to_string()];
+        let headers = cors
+            .preflight_headers(
+                &preflight("https://a.test", "PUT", Some("content-type")),
+                &routes,
+            )
+            .unwrap();
+        assert_eq!(
+            headers.get("Access-Control-Allow-Methods"),
+            Some("GET, PUT")
+        );
+        assert_eq!(
+            headers.get("Access-Control-Allow-Headers"),
+            Some("Content-Type")
+        );
+        assert_eq!(headers.get("Access-Control-Max-Age"), Some("600"));
+
+        assert!(cors
+            .preflight_headers(&preflight("https://a.test", "DELETE", None), &routes)
+            .is_none());
+        assert!(cors
+            .preflight_headers(
+                &preflight("https://a.test", "PUT", Some("X-Secret")),
+                &routes
+            )
+            .is_none());
+        assert!(cors
+            .preflight_headers(&preflight("https://b.test", "PUT", None), &routes)
+            .is_none());
+        assert!(cors
+            .preflight_headers(&origin("https://a.test"), &routes)
+            .is_none());
+    }
+
+    #[test]
+    fn preflight_defaults_to_route_methods() {
+        let cors = cors("origins = \"*\"").unwrap();
+        let routes = ["GET".to_string(), "POST".to_string()];
+        let headers = cors
+            .preflight_headers(
+                &preflight("https://a.test", "POST", Some("X-A, X-B")),
+                &routes,
+            )
+            .unwrap();
+        assert_eq!(
+            headers.get("Access-Control-Allow-Methods"),
+            Some("GET, POST")
+        );
+        assert_eq!(
+            headers.get("Access-Control-Allow-Headers"),
+            Some("X-A, X-B")
+        );
+        assert!(cors
+            .preflight_headers(&preflight("https://a.test", "PUT", None), &routes)
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(cors("credentials = true").is_err());
+        assert!(cors("origins = 1").is_err());
+        assert!(cors("origins = \"*\"\nheaders = [\"X-A, X-B\"]").is_err());
+        assert!(cors("origins = \"*\"\nexpose_headers = [\"X\\r\\nY\"]").is_err());
+        assert!(cors("origins = \"*\"\nmax_age = -1").is_err());
+    }
+}
@@ -1,94 +1,134 @@
+use crate::headers::Headers;
 use crate::logger::{LogLevel, Logger};
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub enum HttpCode {
+/// HTTP status code, any value in 100..=599 is accepted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct HttpCode(u16);
+
+macro_rules! http_codes {
+    ($($num:literal $name:ident $reason:literal;)*) => {
+        #[allow(dead_code)]
+        impl HttpCode {
+            $(pub const $name: HttpCode = HttpCode($num);)*
+
+            /// Reason phrase as registered with IANA.
+            pub fn reason(&self) -> Option<&'static str> {
+                match self.0 {
+                    $($num => Some($reason),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+http_codes! {
+    // 1xx Informational
+    100 CONTINUE "Continue";
+    101 SWITCHING_PROTOCOLS "Switching Protocols";
+    102 PROCESSING "Processing";
+    103 EARLY_HINTS "Early Hints";
+
     // 2xx Success
-    OK = 200,
-    Created = 201,
-    Accepted = 202,
-    NoContent = 204,
+    200 OK "OK";
+    201 CREATED "Created";
+    202 ACCEPTED "Accepted";
+    203 NON_AUTHORITATIVE_INFORMATION "Non-Authoritative Information";
+    204 NO_CONTENT "No Content";
+    205 RESET_CONTENT "Reset Content";
+    206 PARTIAL_CONTENT "Partial Content";
+    207 MULTI_STATUS "Multi-Status";
+    208 ALREADY_REPORTED "Already Reported";
+    226 IM_USED "IM Used";
 
     // 3xx Redirection
-    MovedPermanently = 301,
-    Found = 302,
-    NotModified = 304,
-    TemporaryRedirect = 307,
-    PermanentRedirect = 308,
+    300 MULTIPLE_CHOICES "Multiple Choices";
+    301 MOVED_PERMANENTLY "Moved Permanently";
+    302 FOUND "Found";
+    303 SEE_OTHER "See Other";
+    304 NOT_MODIFIED "Not Modified";
+    305 USE_PROXY "Use Proxy";
+    307 TEMPORARY_REDIRECT "Temporary Redirect";
+    308 PERMANENT_REDIRECT "Permanent Redirect";
 
     // 4xx Client Errors
-    BadRequest = 400,
-    Unauthorized = 401,
-    PaymentRequired = 402,
-    Forbidden = 403,
-    NotFound = 404,
-    MethodNotAllowed = 405,
-    NotAcceptable = 406,
-    Conflict = 409,
-    Gone = 410,
-    LengthRequired = 411,
-    PreconditionFailed = 412,
-    PayloadTooLarge = 413,
-    UnsupportedMediaType = 415,
-    TooManyRequests = 429,
+    400 BAD_REQUEST "Bad Request";
+    401 UNAUTHORIZED "Unauthorized";
+    402 PAYMENT_REQUIRED "Payment Required";
+    403 FORBIDDEN "Forbidden";
+    404 NOT_FOUND "Not Found";
+    405 METHOD_NOT_ALLOWED "Method Not Allowed";
+    406 NOT_ACCEPTABLE "Not Acceptable";
+    407 PROXY_AUTHENTICATION_REQUIRED "Proxy Authentication Required";
+    408 REQUEST_TIMEOUT "Request Timeout";
+    409 CONFLICT "Conflict";
+    410 GONE "Gone";
+    411 LENGTH_REQUIRED "Length Required";
+    412 PRECONDITION_FAILED "Precondition Failed";
+    413 CONTENT_TOO_LARGE "Content Too Large";
+    414 URI_TOO_LONG "URI Too Long";
+    415 UNSUPPORTED_MEDIA_TYPE "Unsupported Media Type";
+    416 RANGE_NOT_SATISFIABLE "Range Not Satisfiable";
+    417 EXPECTATION_FAILED "Expectation Failed";
+    418 IM_A_TEAPOT "I'm a teapot";
+    421 MISDIRECTED_REQUEST "Misdirected Request";
+    422 UNPROCESSABLE_CONTENT "Unprocessable Content";
+    423 LOCKED "Locked";
+    424 FAILED_DEPENDENCY "Failed Dependency";
+    425 TOO_EARLY "Too Early";
+    426 UPGRADE_REQUIRED "Upgrade Required";
+    428 PRECONDITION_REQUIRED "Precondition Required";
+    429 TOO_MANY_REQUESTS "Too Many Requests";
+    431 REQUEST_HEADER_FIELDS_TOO_LARGE "Request Header Fields Too Large";
+    451 UNAVAILABLE_FOR_LEGAL_REASONS "Unavailable For Legal Reasons";
 
     // 5xx Server Errors
-    InternalError = 500,
-    NotImplemented = 501,
-    BadGateway = 502,
-    ServiceUnavailable = 503,
-    GatewayTimeout = 504,
+    500 INTERNAL_ERROR "Internal Server Error";
+    501 NOT_IMPLEMENTED "Not Implemented";
+    502 BAD_GATEWAY "Bad Gateway";
+    503 SERVICE_UNAVAILABLE "Service Unavailable";
+    504 GATEWAY_TIMEOUT "Gateway Timeout";
+    505 HTTP_VERSION_NOT_SUPPORTED "HTTP Version Not Supported";
+    506 VARIANT_ALSO_NEGOTIATES "Variant Also Negotiates";
+    507 INSUFFICIENT_STORAGE "Insufficient Storage";
+    508 LOOP_DETECTED "Loop Detected";
+    510 NOT_EXTENDED "Not Extended";
+    511 NETWORK_AUTHENTICATION_REQUIRED "Network Authentication Required";
 }
 
 impl HttpCode {
-    pub fn to_num(&self) -> u16 {
-        self.to_owned() as u16
+    pub fn to_num(self) -> u16 {
+        self.0
     }
 
     pub fn from_num(num: u16) -> Option<Self> {
-        match num {
-            // 2xx Success
-            200 => Some(HttpCode::OK),
-            201 => Some(HttpCode::Created),
-            202 => Some(HttpCode::Accepted),
-            204 => Some(HttpCode::NoContent),
-
-            // 3xx Redirection
-            301 => Some(HttpCode::MovedPermanently),
-            302 => Some(HttpCode::Found),
-            304 => Some(HttpCode::NotModified),
-            307 => Some(HttpCode::TemporaryRedirect),
-            308 => Some(HttpCode::PermanentRedirect),
-
-            // 4xx Client Errors
-            400 => Some(HttpCode::BadRequest),
-            401 => Some(HttpCode::Unauthorized),
-            402 => Some(HttpCode::PaymentRequired),
-            403 => Some(HttpCode::Forbidden),
-            404 => Some(HttpCode::NotFound),
-            405 => Some(HttpCode::MethodNotAllowed),
-            406 => Some(HttpCode::NotAcceptable),
-            409 => Some(HttpCode::Conflict),
-            410 => Some(HttpCode::Gone),
-            411 => Some(HttpCode::LengthRequired),
-            412 => Some(HttpCode::PreconditionFailed),
-            413 => Some(HttpCode::PayloadTooLarge),
-            415 => Some(HttpCode::UnsupportedMediaType),
-            429 => Some(HttpCode::TooManyRequests),
-
-            // 5xx Server Errors
-            500 => Some(HttpCode::InternalError),
-            501 => Some(HttpCode::NotImplemented),
-            502 => Some(HttpCode::BadGateway),
-            503 => Some(HttpCode::ServiceUnavailable),
-            504 => Some(HttpCode::GatewayTimeout),
-
-            _ => None,
+        if (100..=599).contains(&num) {
+            Some(HttpCode(num))
+        } else {
+            None
         }
     }
+
+    /// Reason phrase for the status line, falling back to the code's class
+    /// for codes that are not registered.
+    pub fn canonical_reason(&self) -> &'static str {
+        self.reason().unwrap_or(match self.0 {
+            100..=199 => "Informational",
+            200..=299 => "Success",
+            300..=399 => "Redirection",
+            400..=499 => "Client Error",
+            _ => "Server Error",
+        })
+    }
+
+    /// 1xx, 204 and 304 responses never carry a body (RFC 9110, section 6.4.1).
+    pub fn allows_body(&self) -> bool {
+        !matches!(self.0, 100..=199 | 204 | 304)
+    }
 }
 
 pub type NetResult<T> = Result<T, NetError>;
@@ -97,6 +137,7 @@ pub type NetResult<T> = Result<T, NetError>;
 pub struct NetError {
     pub erc: HttpCode,
     pub details: String,
+    pub headers: Headers,
 }
 
 impl NetError {
@@ -109,8 +150,15 @@ impl NetError {
         Self {
             erc,
             details: details_x,
+            headers: Headers::new(),
         }
     }
+
+    /// Adds a header to the error response (e.g. `WWW-Authenticate` or `Retry-After`).
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        let _ = self.headers.append(name, value);
+        self
+    }
 }
 
 impl Display for NetError {
@@ -119,6 +167,8 @@ impl Display for NetError {
     }
 }
 
+impl std::error::Error for NetError {}
+
 pub type DogResult<T> = Result<T, DogError>;
 
 #[derive(Clone, Debug)]
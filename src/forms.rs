@@ -0,0 +1,630 @@
+use crate::errors::{HttpCode, NetError, NetResult};
+use crate::lualib::url_decode;
+use crate::request::HttpRequest;
+use std::fs;
+use std::path::PathBuf;
+
+/// Limits on form bodies parsed for scripts, configured in `[limits]`.
+#[derive(Clone, Copy, Debug)]
+pub struct FormLimits {
+    /// Size of a single text field in bytes
+    pub field: usize,
+    /// Size of a single uploaded file in bytes
+    pub file: usize,
+    /// Number of fields and files
+    pub parts: usize,
+    /// Size of all fields and files together in bytes
+    pub total: usize,
+    /// Uploads above this size go to `upload_dir` instead of memory
+    pub memory: usize,
+}
+
+impl Default for FormLimits {
+    fn default() -> Self {
+        Self {
+            field: 64 * 1024,
+            file: 1024 * 1024,
+            parts: 100,
+            total: 1024 * 1024,
+            memory: 256 * 1024,
+        }
+    }
+}
+
+/// How forms are parsed, shared by all Lua states.
+#[derive(Clone, Debug, Default)]
+pub struct FormConfig {
+    pub limits: FormLimits,
+    pub upload_dir: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum FileData {
+    Memory(Vec<u8>),
+    /// Written to `upload_dir`, removed once the script is done
+    Temp(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct UploadedFile {
+    pub name: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: usize,
+    pub data: FileData,
+}
+
+#[derive(Debug, Default)]
+pub struct Form {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<UploadedFile>,
+}
+
+impl Form {
+    /// Uploads written to `upload_dir`, to be removed after the request.
+    pub fn temp_files(&self) -> Vec<PathBuf> {
+        self.files
+            .iter()
+            .filter_map(|t| match &t.data {
+                FileData::Temp(path) => Some(path.clone()),
+                FileData::Memory(_) => None,
+            })
+            .collect()
+    }
+}
+
+fn bad_request(details: &str) -> NetError {
+    NetError::new(HttpCode::BAD_REQUEST, Some(details.to_string()))
+}
+
+fn too_large(details: String) -> NetError {
+    NetError::new(HttpCode::CONTENT_TOO_LARGE, Some(details))
+}
+
+/// Splits `value; key=value; key="quoted value"` into the value and its lowercased parameters.
+/// Backslashes are kept, browsers send Windows paths in file names unescaped and quotes as `%22`.
+fn split_params(header: &str) -> (String, Vec<(String, String)>) {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for t in header.chars() {
+        match t {
+            '"' => quoted = !quoted,
+            ';' if !quoted => parts.push(std::mem::take(&mut current)),
+            t => current.push(t),
+        }
+    }
+    parts.push(current);
+
+    let value = parts.remove(0).trim().to_ascii_lowercase();
+    let params = parts
+        .iter()
+        .filter_map(|t| t.split_once('='))
+        .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    (value, params)
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|t| t == needle)
+        .map(|t| t + from)
+}
+
+/// Keeps track of the part count and total size while parsing.
+struct Budget {
+    limits: FormLimits,
+    parts: usize,
+    total: usize,
+}
+
+impl Budget {
+    fn take(&mut self, name: &str, size: usize, is_file: bool) -> NetResult<()> {
+        self.parts += 1;
+        self.total += size;
+        if self.parts > self.limits.parts {
+            return Err(too_large(format!(
+                "Form has more than {} parts",
+                self.limits.parts
+            )));
+        }
+        let limit = if is_file {
+            self.limits.file
+        } else {
+            self.limits.field
+        };
+        if size > limit {
+            return Err(too_large(format!("Form part '{}' too large", name)));
+        }
+        if self.total > self.limits.total {
+            return Err(too_large("Form too large".to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn parse_urlencoded(body: &[u8], budget: &mut Budget) -> NetResult<Form> {
+    let mut form = Form::default();
+    for pair in body.split(|t| *t == b'&').filter(|t| !t.is_empty()) {
+        let (name, value) = match pair.iter().position(|t| *t == b'=') {
+            Some(i) => (&pair[..i], &pair[i + 1..]),
+            None => (pair, &[][..]),
+        };
+        let name = String::from_utf8(url_decode(name, true))
+            .map_err(|_| bad_request("Form field names must be UTF-8"))?;
+        let value = String::from_utf8(url_decode(value, true))
+            .map_err(|_| bad_request("Form fields must be UTF-8"))?;
+        budget.take(&name, value.len(), false)?;
+        form.fields.push((name, value));
+    }
+    Ok(form)
+}
+
+/// Stores an upload in memory, or in `upload_dir` if it is large.
+fn store_file(data: &[u8], config: &FormConfig) -> NetResult<FileData> {
+    let upload_dir = match &config.upload_dir {
+        Some(upload_dir) if data.len() > config.limits.memory => upload_dir,
+        _ => return Ok(FileData::Memory(data.to_vec())),
+    };
+    let mut id = [0u8; 16];
+    let path = getrandom::fill(&mut id).ok().map(|_| {
+        let id: String = id.iter().map(|t| format!("{:02x}", t)).collect();
+        upload_dir.join(format!("netpup-upload-{}", id))
+    });
+    match path {
+        Some(path) if fs::write(&path, data).is_ok() => Ok(FileData::Temp(path)),
+        _ => Err(NetError::new(
+            HttpCode::INTERNAL_ERROR,
+            Some("Could not store upload".to_string()),
+        )),
+    }
+}
+
+fn parse_multipart(
+    body: &[u8],
+    boundary: &str,
+    budget: &mut Budget,
+    config: &FormConfig,
+    form: &mut Form,
+) -> NetResult<()> {
+    let malformed = || bad_request("Malformed multipart body");
+    if boundary.is_empty() || boundary.len() > 70 {
+        return Err(bad_request("Missing or invalid multipart boundary"));
+    }
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let next_delimiter = format!("\r\n--{}", boundary).into_bytes();
+
+    // Anything before the first delimiter is a preamble to ignore
+    let mut pos = find(body, &delimiter, 0).ok_or_else(malformed)? + delimiter.len();
+    loop {
+        match body.get(pos..pos + 2) {
+            Some(b"--") => break,
+            Some(b"\r\n") => pos += 2,
+            _ => return Err(malformed()),
+        }
+        let head_end = find(body, b"\r\n\r\n", pos).ok_or_else(malformed)?;
+        let head = std::str::from_utf8(&body[pos..head_end]).map_err(|_| malformed())?;
+        let content_start = head_end + 4;
+        let content_end = find(body, &next_delimiter, content_start).ok_or_else(malformed)?;
+        let content = &body[content_start..content_end];
+        pos = content_end + next_delimiter.len();
+
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in head.split("\r\n").filter(|t| !t.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(malformed)?;
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-disposition" => disposition = Some(split_params(value)),
+                "content-type" => content_type = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+        let (kind, params) = disposition.ok_or_else(malformed)?;
+        let param = |key: &str| params.iter().find(|t| t.0 == key).map(|t| t.1.clone());
+        let name = match param("name") {
+            Some(name) if kind == "form-data" => name,
+            _ => return Err(malformed()),
+        };
+        match param("filename") {
+            Some(filename) => {
+                budget.take(&name, content.len(), true)?;
+                // Browsers may send a full path, only the file name is of interest
+                let filename = filename
+                    .rsplit(['/', '\\'])
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                form.files.push(UploadedFile {
+                    name,
+                    filename,
+                    content_type: content_type
+                        .unwrap_or_else(|| "application/octet-stream".to_string()),
+                    size: content.len(),
+                    data: store_file(content, config)?,
+                });
+            }
+            None => {
+                budget.take(&name, content.len(), false)?;
+                let value = String::from_utf8(content.to_vec())
+                    .map_err(|_| bad_request("Form fields must be UTF-8"))?;
+                form.fields.push((name, value));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses an `application/x-www-form-urlencoded` or `multipart/form-data` body.
+/// Other content types are answered with 415, broken bodies with 400 and exceeded limits with 413.
+pub fn parse(request: &HttpRequest, config: &FormConfig) -> NetResult<Form> {
+    let (media_type, params) = split_params(request.headers.get("Content-Type").unwrap_or(""));
+    let mut budget = Budget {
+        limits: config.limits,
+        parts: 0,
+        total: 0,
+    };
+    match media_type.as_str() {
+        "application/x-www-form-urlencoded" => parse_urlencoded(&request.body, &mut budget),
+        "multipart/form-data" => {
+            let boundary = params
+                .iter()
+                .find(|t| t.0 == "boundary")
+                .map(|t| t.1.as_str())
+                .unwrap_or("");
+            let mut form = Form::default();
+            if let Err(e) = parse_multipart(&request.body, boundary, &mut budget, config, &mut form)
+            {
+                // Files stored before the error would be left behind otherwise
+                for path in form.temp_files() {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(e);
+            }
+            Ok(form)
+        }
+        _ => Err(NetError::new(
+            HttpCode::UNSUPPORTED_MEDIA_TYPE,
+            Some("Expected a form body".to_string()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARY: &str = "XyZ";
+
+    fn request(content_type: Option<&str>, body: &[u8]) -> HttpRequest {
+        let mut lines = vec!["POST /form HTTP/1.1".to_string()];
+        lines.extend(content_type.map(|t| format!("Content-Type: {}", t)));
+        let mut request = HttpRequest::from_raw(lines).unwrap();
+        request.body = body.to_vec();
+        request
+    }
+
+    fn urlencoded(body: &str, config: &FormConfig) -> NetResult<Form> {
+        parse(
+            &request(Some("application/x-www-form-urlencoded"), body.as_bytes()),
+            config,
+        )
+    }
+
+    fn multipart(body: &[u8], config: &FormConfig) -> NetResult<Form> {
+        let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+        parse(&request(Some(&content_type), body), config)
+    }
+
+    fn part(disposition: &str, content_type: Option<&str>, data: &[u8]) -> Vec<u8> {
+        let mut part = format!("--{}\r\nContent-Disposition: {}\r\n", BOUNDARY, disposition);
+        if let Some(content_type) = content_type {
+            part += format!("Content-Type: {}\r\n", content_type).as_str();
+        }
+        [part.as_bytes(), b"\r\n", data, b"\r\n"].concat()
+    }
+
+    fn end() -> Vec<u8> {
+        format!("--{}--\r\n", BOUNDARY).into_bytes()
+    }
+
+    fn error_code<T: std::fmt::Debug>(result: NetResult<T>) -> HttpCode {
+        result.unwrap_err().erc
+    }
+
+    /// A fresh directory for uploads, removed by the caller.
+    fn upload_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("netpup-forms-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn urlencoded_fields() {
+        let form = urlencoded(
+            "a=1&b=hello+w%C3%B6rld&tags=x&tags=y&empty=&flag&&c=%3D%26",
+            &FormConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            form.fields,
+            [
+                ("a", "1"),
+                ("b", "hello wörld"),
+                ("tags", "x"),
+                ("tags", "y"),
+                ("empty", ""),
+                ("flag", ""),
+                ("c", "=&"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+        assert!(form.files.is_empty());
+        assert!(urlencoded("", &FormConfig::default())
+            .unwrap()
+            .fields
+            .is_empty());
+    }
+
+    #[test]
+    fn urlencoded_rejects_invalid_utf8() {
+        let config = FormConfig::default();
+        assert_eq!(
+            error_code(urlencoded("a=%FF", &config)),
+            HttpCode::BAD_REQUEST
+        );
+        assert_eq!(
+            error_code(urlencoded("%C3=1", &config)),
+            HttpCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn media_type_is_checked() {
+        let config = FormConfig::default();
+        assert_eq!(
+            error_code(parse(&request(None, b"a=1"), &config)),
+            HttpCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            error_code(parse(&request(Some("text/plain"), b"a=1"), &config)),
+            HttpCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            error_code(parse(&request(Some("application/json"), b"{}"), &config)),
+            HttpCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        let form = parse(
+            &request(
+                Some("Application/X-WWW-Form-Urlencoded; charset=UTF-8"),
+                b"a=1",
+            ),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(form.fields.len(), 1);
+    }
+
+    #[test]
+    fn urlencoded_limits() {
+        let config = FormConfig {
+            limits: FormLimits {
+                field: 4,
+                parts: 3,
+                total: 10,
+                ..FormLimits::default()
+            },
+            upload_dir: None,
+        };
+        assert!(urlencoded("a=1234", &config).is_ok());
+        assert_eq!(
+            error_code(urlencoded("a=12345", &config)),
+            HttpCode::CONTENT_TOO_LARGE
+        );
+        // Sizes are counted after decoding
+        assert!(urlencoded("a=%31%32%33%34", &config).is_ok());
+        assert!(urlencoded("a=1&b=2&c=3", &config).is_ok());
+        assert_eq!(
+            error_code(urlencoded("a=1&b=2&c=3&d=4", &config)),
+            HttpCode::CONTENT_TOO_LARGE
+        );
+        assert!(urlencoded("a=1234&b=1234&c=12", &config).is_ok());
+        assert_eq!(
+            error_code(urlencoded("a=1234&b=1234&c=123", &config)),
+            HttpCode::CONTENT_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn multipart_fields_and_files() {
+        let body = [
+            b"preamble\r\n".to_vec(),
+            part("form-data; name=\"title\"", None, b"hi there"),
+            part(
+                "form-data; name=\"up\"; filename=\"C:\\dir\\a.bin\"",
+                Some("application/x-thing"),
+                b"\x00\x01 --XyZ inside",
+            ),
+            part("form-data; name=\"up\"; filename=\"/tmp/b.txt\"", None, b""),
+            part("form-data; name=\"a;b\"", None, b"semicolon"),
+            end(),
+            b"epilogue".to_vec(),
+        ]
+        .concat();
+        let form = multipart(&body, &FormConfig::default()).unwrap();
+        assert_eq!(
+            form.fields,
+            [("title", "hi there"), ("a;b", "semicolon")]
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+        assert_eq!(form.files.len(), 2);
+
+        let first = &form.files[0];
+        assert_eq!(first.name, "up");
+        assert_eq!(first.filename, "a.bin");
+        assert_eq!(first.content_type, "application/x-thing");
+        assert_eq!(first.size, 15);
+        assert!(matches!(&first.data, FileData::Memory(data) if data == b"\x00\x01 --XyZ inside"));
+
+        let second = &form.files[1];
+        assert_eq!(second.filename, "b.txt");
+        assert_eq!(second.content_type, "application/octet-stream");
+        assert_eq!(second.size, 0);
+    }
+
+    #[test]
+    fn multipart_quoted_boundary() {
+        let body = [part("form-data; name=\"a\"", None, b"1"), end()].concat();
+        let form = parse(
+            &request(Some("multipart/form-data; boundary=\"XyZ\""), &body),
+            &FormConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(form.fields, vec![("a".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn multipart_rejects_bad_boundaries() {
+        let body = [part("form-data; name=\"a\"", None, b"1"), end()].concat();
+        let config = FormConfig::default();
+        assert_eq!(
+            error_code(parse(&request(Some("multipart/form-data"), &body), &config)),
+            HttpCode::BAD_REQUEST
+        );
+        let long = format!("multipart/form-data; boundary={}", "x".repeat(71));
+        assert_eq!(
+            error_code(parse(&request(Some(&long), &body), &config)),
+            HttpCode::BAD_REQUEST
+        );
+        let other = "multipart/form-data; boundary=other";
+        assert_eq!(
+            error_code(parse(&request(Some(other), &body), &config)),
+            HttpCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn multipart_rejects_malformed_bodies() {
+        let config = FormConfig::default();
+        let cases: Vec<Vec<u8>> = vec![
+            // No final delimiter
+            part("form-data; name=\"a\"", None, b"1"),
+            // Part never ends
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1".to_vec(),
+            // Head never ends
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"".to_vec(),
+            // Garbage after the delimiter
+            b"--XyZxx\r\n".to_vec(),
+            [b"--XyZ\r\nNo-Colon\r\n\r\n1\r\n".to_vec(), end()].concat(),
+            [b"--XyZ\r\n\r\n1\r\n".to_vec(), end()].concat(),
+            [part("attachment; name=\"a\"", None, b"1"), end()].concat(),
+            [part("form-data; filename=\"a.txt\"", None, b"1"), end()].concat(),
+            b"".to_vec(),
+        ];
+        for body in cases {
+            assert_eq!(
+                error_code(multipart(&body, &config)),
+                HttpCode::BAD_REQUEST,
+                "{}",
+                String::from_utf8_lossy(&body)
+            );
+        }
+        let body = [part("form-data; name=\"a\"", None, b"\xff"), end()].concat();
+        assert_eq!(error_code(multipart(&body, &config)), HttpCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn multipart_limits() {
+        let config = FormConfig {
+            limits: FormLimits {
+                field: 4,
+                file: 8,
+                parts: 2,
+                total: 12,
+                ..FormLimits::default()
+            },
+            upload_dir: None,
+        };
+        let field = |data: &[u8]| part("form-data; name=\"f\"", None, data);
+        let file = |data: &[u8]| part("form-data; name=\"u\"; filename=\"u\"", None, data);
+
+        assert!(multipart(&[field(b"1234"), end()].concat(), &config).is_ok());
+        assert_eq!(
+            error_code(multipart(&[field(b"12345"), end()].concat(), &config)),
+            HttpCode::CONTENT_TOO_LARGE
+        );
+        assert!(multipart(&[file(b"12345678"), end()].concat(), &config).is_ok());
+        assert_eq!(
+            error_code(multipart(&[file(b"123456789"), end()].concat(), &config)),
+            HttpCode::CONTENT_TOO_LARGE
+        );
+        assert!(multipart(
+            &[field(b"1234"), file(b"12345678"), end()].concat(),
+            &config
+        )
+        .is_ok());
+        assert_eq!(
+            error_code(multipart(
+                &[field(b"12345"), file(b"12345678"), end()].concat(),
+                &config
+            )),
+            HttpCode::CONTENT_TOO_LARGE
+        );
+        assert_eq!(
+            error_code(multipart(
+                &[field(b"1"), field(b"2"), field(b"3"), end()].concat(),
+                &config
+            )),
+            HttpCode::CONTENT_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn large_uploads_go_to_upload_dir() {
+        let dir = upload_dir("temp");
+        let config = FormConfig {
+            limits: FormLimits {
+                memory: 4,
+                ..FormLimits::default()
+            },
+            upload_dir: Some(dir.clone()),
+        };
+        let body = [
+            part("form-data; name=\"small\"; filename=\"s\"", None, b"1234"),
+            part("form-data; name=\"large\"; filename=\"l\"", None, b"12345"),
+            end(),
+        ]
+        .concat();
+        let form = multipart(&body, &config).unwrap();
+        assert!(matches!(form.files[0].data, FileData::Memory(_)));
+        let temp_files = form.temp_files();
+        assert_eq!(temp_files.len(), 1);
+        assert!(temp_files[0].starts_with(&dir));
+        assert_eq!(fs::read(&temp_files[0]).unwrap(), b"12345");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn uploads_are_removed_on_errors() {
+        let dir = upload_dir("error");
+        let config = FormConfig {
+            limits: FormLimits {
+                memory: 0,
+                ..FormLimits::default()
+            },
+            upload_dir: Some(dir.clone()),
+        };
+        // The second part is never terminated
+        let body = [
+            part("form-data; name=\"u\"; filename=\"u\"", None, b"data"),
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n".to_vec(),
+        ]
+        .concat();
+        assert_eq!(error_code(multipart(&body, &config)), HttpCode::BAD_REQUEST);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::errors::{HttpCode, NetError, NetResult};
+use serde::{Deserialize, Serialize};
+
+/// Ordered HTTP header map.
+/// Keeps insertion order and duplicate names (e.g. several `Set-Cookie`),
+/// while lookups ignore the case of the header name.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self { entries: vec![] }
+    }
+
+    /// Checks a header name / value pair, rejecting anything that could be used
+    /// for header injection (CR / LF / NUL in values, non-token names).
+    pub fn validate(name: &str, value: &str) -> NetResult<()> {
+        if name.is_empty() || !name.chars().all(is_token_char) {
+            return Err(NetError::new(
+                HttpCode::BAD_REQUEST,
+                Some(format!("Invalid header name '{}'", name.escape_debug())),
+            ));
+        }
+        if value.chars().any(|c| c == '\r' || c == '\n' || c == '\0') {
+            return Err(NetError::new(
+                HttpCode::BAD_REQUEST,
+                Some(format!("Invalid value for header '{}'", name)),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Adds a header, keeping any existing ones with the same name.
+    pub fn append(&mut self, name: &str, value: &str) -> NetResult<()> {
+        Self::validate(name, value)?;
+        self.entries
+            .push((name.to_string(), value.trim().to_string()));
+        Ok(())
+    }
+
+    /// Sets a header, replacing all existing ones with the same name.
+    /// The new value takes the position of the first replaced header.
+    pub fn insert(&mut self, name: &str, value: &str) -> NetResult<()> {
+        Self::validate(name, value)?;
+        let value = value.trim().to_string();
+        match self.position(name) {
+            Some(pos) => {
+                self.entries[pos].1 = value;
+                let mut seen = false;
+                self.entries.retain(|(n, _)| {
+                    if !n.eq_ignore_ascii_case(name) {
+                        return true;
+                    }
+                    let keep = !seen;
+                    seen = true;
+                    keep
+                });
+            }
+            None => self.entries.push((name.to_string(), value)),
+        }
+        Ok(())
+    }
+
+    /// Sets a header only if no header with that name exists yet.
+    pub fn insert_default(&mut self, name: &str, value: &str) -> NetResult<()> {
+        if self.contains(name) {
+            return Ok(());
+        }
+        self.append(name, value)
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(n, _)| n.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the first value for the given header name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.position(name).map(|pos| self.entries[pos].1.as_str())
+    }
+
+    /// Returns all values for the given header name, in order.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    /// Appends every header of `other`.
+    pub fn extend(&mut self, other: &Headers) {
+        self.entries.extend(other.entries.iter().cloned());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(headers: &Headers) -> Vec<&str> {
+        headers.iter().map(|(n, _)| n).collect()
+    }
+
+    #[test]
+    fn validate_rejects_injection() {
+        assert!(Headers::validate("X-Test", "value").is_ok());
+        assert!(Headers::validate("X-Test", "").is_ok());
+        for value in ["a\r\nSet-Cookie: x=1", "a\rb", "a\nb", "a\0b"] {
+            let error = Headers::validate("X-Test", value).unwrap_err();
+            assert_eq!(error.erc, HttpCode::BAD_REQUEST);
+        }
+        for name in ["", "X Test", "X-Test:", "X\r\nTest", "X\0", "Ü"] {
+            assert!(Headers::validate(name, "value").is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn append_and_insert_validate() {
+        let mut headers = Headers::new();
+        assert!(headers.append("X-Test", "a\r\nb").is_err());
+        assert!(headers.insert("X-Test", "a\nb").is_err());
+        assert!(headers.insert("Bad Name", "a").is_err());
+        assert_eq!(headers.iter().count(), 0);
+    }
+
+    #[test]
+    fn lookup_ignores_case() {
+        let mut headers = Headers::new();
+        headers.append("Content-Type", " text/html ").unwrap();
+        assert_eq!(headers.get("content-type"), Some("text/html"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/html"));
+        assert_eq!(headers.get("Content-Length"), None);
+    }
+
+    #[test]
+    fn append_keeps_order_and_duplicates() {
+        let mut headers = Headers::new();
+        headers.append("Set-Cookie", "a=1").unwrap();
+        headers.append("X-Other", "x").unwrap();
+        headers.append("set-cookie", "b=2").unwrap();
+        assert_eq!(names(&headers), vec!["Set-Cookie", "X-Other", "set-cookie"]);
+        assert_eq!(headers.get("Set-Cookie"), Some("a=1"));
+    }
+
+    #[test]
+    fn insert_replaces_in_place() {
+        let mut headers = Headers::new();
+        headers.append("A", "1").unwrap();
+        headers.append("Vary", "Origin").unwrap();
+        headers.append("B", "2").unwrap();
+        headers.append("VARY", "Accept").unwrap();
+        headers.insert("vary", "Cookie").unwrap();
+        assert_eq!(names(&headers), vec!["A", "Vary", "B"]);
+        assert_eq!(headers.get("Vary"), Some("Cookie"));
+
+        headers.insert("C", "3").unwrap();
+        assert_eq!(names(&headers), vec!["A", "Vary", "B", "C"]);
+    }
+}
@@ -105,6 +105,16 @@ impl Logger {
         self.log(LogLevel::INFO, message);
     }
 
+    /// Makes sure everything logged so far reached the terminal and the log file.
+    pub fn flush(&self) {
+        let _ = std::io::stdout().flush();
+        if let Some(write_file) = &self.write_file {
+            if let Ok(file) = OpenOptions::new().append(true).open(write_file) {
+                let _ = file.sync_all();
+            }
+        }
+    }
+
     pub fn fatal(&mut self, message: &str) {
         self.log(LogLevel::FATAL, message);
         DogError::__terminate();
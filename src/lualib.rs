@@ -0,0 +1,442 @@
+use crate::cookies::{SameSite, SetCookie};
+use crate::errors::HttpCode;
+use mlua::prelude::LuaError;
+use mlua::{IntoLua, Lua, Table, Value};
+
+/// Upper bound for `util.random_hex`, the buffer is allocated outside the Lua memory limit.
+const MAX_RANDOM_BYTES: usize = 1024;
+
+/// Creates the table of a module built into netpup, `None` if there is no such module.
+/// Built-in modules take precedence over files in `lua_path`.
+pub fn builtin_module(lua: &Lua, name: &str) -> Option<Result<Table, LuaError>> {
+    match name {
+        "json" => Some(json(lua)),
+        "http" => Some(http(lua)),
+        "util" => Some(util(lua)),
+        "cookie" => Some(cookie(lua)),
+        _ => None,
+    }
+}
+
+/// Decodes `%XX` escapes, with `plus_as_space` also `+` as used by HTML forms.
+/// Malformed escapes are kept as they are.
+pub fn url_decode(s: &[u8], plus_as_space: bool) -> Vec<u8> {
+    let hex = |t: u8| (t as char).to_digit(16).map(|t| t as u8);
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match (
+            s[i],
+            s.get(i + 1).and_then(|t| hex(*t)),
+            s.get(i + 2).and_then(|t| hex(*t)),
+        ) {
+            (b'%', Some(high), Some(low)) => {
+                out.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+            (b'+', _, _) if plus_as_space => out.push(b' '),
+            (t, _, _) => out.push(t),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// Escapes everything but the unreserved characters of RFC 3986.
+pub fn url_encode(s: &[u8]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for t in s {
+        match t {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(*t as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", t)),
+        }
+    }
+    out
+}
+
+/// Deepest nesting `json.encode` follows, deeper tables are most likely cycles.
+const JSON_MAX_DEPTH: usize = 128;
+
+/// Registry keys of the metatables marking tables as JSON arrays or objects.
+const JSON_ARRAY: &str = "netpup_json_array";
+const JSON_OBJECT: &str = "netpup_json_object";
+
+fn json_marker(lua: &Lua, key: &str) -> Result<Table, LuaError> {
+    match lua.named_registry_value::<Option<Table>>(key)? {
+        Some(marker) => Ok(marker),
+        None => {
+            let marker = lua.create_table()?;
+            lua.set_named_registry_value(key, &marker)?;
+            Ok(marker)
+        }
+    }
+}
+
+fn json_error(message: String) -> LuaError {
+    LuaError::runtime(format!("json.encode: {}", message))
+}
+
+/// Converts a Lua value for encoding. Tables marked by `json.array` / `json.object` keep their kind,
+/// other tables are arrays if their keys are exactly 1..n, and objects otherwise.
+pub fn lua_to_json(lua: &Lua, value: &Value, depth: usize) -> Result<serde_json::Value, LuaError> {
+    if depth > JSON_MAX_DEPTH {
+        return Err(json_error(
+            "tables nested too deep (or a cycle)".to_string(),
+        ));
+    }
+    Ok(match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::LightUserData(t) if t.0.is_null() => serde_json::Value::Null,
+        Value::Boolean(t) => serde_json::Value::Bool(*t),
+        Value::Integer(t) => serde_json::Value::Number((*t).into()),
+        Value::Number(t) => serde_json::Number::from_f64(*t)
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| json_error("NaN and infinity can not be encoded".to_string()))?,
+        Value::String(t) => serde_json::Value::String(
+            t.to_str()
+                .map_err(|_| json_error("strings must be UTF-8".to_string()))?
+                .to_string(),
+        ),
+        Value::Table(t) => {
+            let marker = t.metatable();
+            let is_array = match marker {
+                Some(marker) if marker == json_marker(lua, JSON_ARRAY)? => true,
+                Some(marker) if marker == json_marker(lua, JSON_OBJECT)? => false,
+                _ => {
+                    let len = t.raw_len();
+                    len > 0 && t.pairs::<Value, Value>().count() == len
+                }
+            };
+            if is_array {
+                let mut array = vec![];
+                for i in 1..=t.raw_len() {
+                    array.push(lua_to_json(lua, &t.raw_get(i)?, depth + 1)?);
+                }
+                serde_json::Value::Array(array)
+            } else {
+                let mut pairs = vec![];
+                for pair in t.pairs::<Value, Value>() {
+                    let (key, value) = pair?;
+                    let key = match key {
+                        Value::String(t) => t
+                            .to_str()
+                            .map_err(|_| json_error("keys must be UTF-8".to_string()))?
+                            .to_string(),
+                        Value::Integer(t) => t.to_string(),
+                        key => {
+                            return Err(json_error(format!(
+                                "can not use a {} as object key",
+                                key.type_name()
+                            )))
+                        }
+                    };
+                    pairs.push((key, lua_to_json(lua, &value, depth + 1)?));
+                }
+                // Lua tables have no order, sorted keys keep the output stable
+                pairs.sort_by(|a, b| a.0.cmp(&b.0));
+                serde_json::Value::Object(pairs.into_iter().collect())
+            }
+        }
+        value => {
+            return Err(json_error(format!(
+                "can not encode a {}",
+                value.type_name()
+            )))
+        }
+    })
+}
+
+pub fn json_to_lua(lua: &Lua, value: serde_json::Value) -> Result<Value, LuaError> {
+    Ok(match value {
+        serde_json::Value::Null => Value::NULL,
+        serde_json::Value::Bool(t) => Value::Boolean(t),
+        serde_json::Value::Number(t) => match t.as_i64() {
+            Some(t) => Value::Integer(t),
+            None => Value::Number(t.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(t) => Value::String(lua.create_string(t)?),
+        serde_json::Value::Array(t) => {
+            let array = lua.create_table_with_capacity(t.len(), 0)?;
+            for value in t {
+                array.raw_push(json_to_lua(lua, value)?)?;
+            }
+            array.set_metatable(Some(json_marker(lua, JSON_ARRAY)?));
+            Value::Table(array)
+        }
+        serde_json::Value::Object(t) => {
+            let object = lua.create_table_with_capacity(0, t.len())?;
+            for (key, value) in t {
+                object.raw_set(key, json_to_lua(lua, value)?)?;
+            }
+            object.set_metatable(Some(json_marker(lua, JSON_OBJECT)?));
+            Value::Table(object)
+        }
+    })
+}
+
+/// Decodes JSON text, errors name the line and column of the problem.
+pub fn json_decode(lua: &Lua, text: &[u8]) -> Result<Value, String> {
+    let value: serde_json::Value = serde_json::from_slice(text).map_err(|e| e.to_string())?;
+    json_to_lua(lua, value).map_err(|e| e.to_string())
+}
+
+fn json_encode(lua: &Lua, (value, options): (Value, Option<Table>)) -> Result<String, LuaError> {
+    let value = lua_to_json(lua, &value, 0)?;
+    let pretty = match &options {
+        Some(options) => options.get::<Option<bool>>("pretty")?.unwrap_or(false),
+        None => false,
+    };
+    if !pretty {
+        return serde_json::to_string(&value).map_err(|e| json_error(e.to_string()));
+    }
+    let indent = match &options {
+        Some(options) => options.get::<Option<usize>>("indent")?.unwrap_or(2),
+        None => 2,
+    };
+    let indent = " ".repeat(indent.min(16));
+    let mut out = vec![];
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+    let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+    serde::Serialize::serialize(&value, &mut serializer).map_err(|e| json_error(e.to_string()))?;
+    Ok(String::from_utf8_lossy(&out).to_string())
+}
+
+/// Marks `table` (or a new table) with the metatable `key`, so it encodes as that kind.
+fn json_mark(lua: &Lua, table: Option<Table>, key: &str) -> Result<Table, LuaError> {
+    let table = table.map_or_else(|| lua.create_table(), Ok)?;
+    table.set_metatable(Some(json_marker(lua, key)?));
+    Ok(table)
+}
+
+fn json(lua: &Lua) -> Result<Table, LuaError> {
+    let module = lua.create_table()?;
+    module.set("null", Value::NULL)?;
+    module.set("encode", lua.create_function(json_encode)?)?;
+    module.set(
+        "decode",
+        lua.create_function(|lua, text: mlua::String| {
+            json_decode(lua, &text.as_bytes())
+                .map_err(|e| LuaError::runtime(format!("json.decode: {}", e)))
+        })?,
+    )?;
+    module.set(
+        "array",
+        lua.create_function(|lua, table: Option<Table>| json_mark(lua, table, JSON_ARRAY))?,
+    )?;
+    module.set(
+        "object",
+        lua.create_function(|lua, table: Option<Table>| json_mark(lua, table, JSON_OBJECT))?,
+    )?;
+    Ok(module)
+}
+
+fn http(lua: &Lua) -> Result<Table, LuaError> {
+    let module = lua.create_table()?;
+    module.set(
+        "reason",
+        lua.create_function(|_, code: u16| {
+            Ok(HttpCode::from_num(code).map(|t| t.canonical_reason().to_string()))
+        })?,
+    )?;
+    module.set(
+        "url_encode",
+        lua.create_function(|_, s: mlua::String| Ok(url_encode(&s.as_bytes())))?,
+    )?;
+    module.set(
+        "url_decode",
+        lua.create_function(|lua, s: mlua::String| {
+            lua.create_string(url_decode(&s.as_bytes(), true))
+        })?,
+    )?;
+    // http.response(code, content, type?, headers?) builds a response table
+    module.set(
+        "response",
+        lua.create_function(
+            |lua, (code, content, ct, headers): (u16, mlua::String, Option<String>, Option<Table>)| {
+                let response = lua.create_table()?;
+                response.set("code", code)?;
+                response.set("headers", headers.map_or_else(|| lua.create_table(), Ok)?)?;
+                response.set("content", content)?;
+                response.set("type", ct)?;
+                Ok(response)
+            },
+        )?,
+    )?;
+    // http.redirect(location, code?) answers with a 302 unless another code is given
+    module.set(
+        "redirect",
+        lua.create_function(|lua, (location, code): (String, Option<u16>)| {
+            let response = lua.create_table()?;
+            let headers = lua.create_table()?;
+            headers.set("Location", location)?;
+            response.set("code", code.unwrap_or(302))?;
+            response.set("headers", headers)?;
+            response.set("content", "")?;
+            Ok(response)
+        })?,
+    )?;
+    Ok(module)
+}
+
+fn util(lua: &Lua) -> Result<Table, LuaError> {
+    let module = lua.create_table()?;
+    // util.split(s, sep) splits at every plain (not pattern) occurrence of `sep`
+    module.set(
+        "split",
+        lua.create_function(|_, (s, sep): (String, String)| {
+            if sep.is_empty() {
+                return Err(LuaError::runtime("Separator must not be empty"));
+            }
+            Ok(s.split(sep.as_str())
+                .map(|t| t.to_string())
+                .collect::<Vec<String>>())
+        })?,
+    )?;
+    module.set(
+        "trim",
+        lua.create_function(|_, s: String| Ok(s.trim().to_string()))?,
+    )?;
+    module.set(
+        "starts_with",
+        lua.create_function(|_, (s, prefix): (String, String)| Ok(s.starts_with(&prefix)))?,
+    )?;
+    module.set(
+        "ends_with",
+        lua.create_function(|_, (s, suffix): (String, String)| Ok(s.ends_with(&suffix)))?,
+    )?;
+    module.set(
+        "escape_html",
+        lua.create_function(|_, s: String| {
+            let mut out = String::with_capacity(s.len());
+            for t in s.chars() {
+                match t {
+                    '&' => out.push_str("&amp;"),
+                    '<' => out.push_str("&lt;"),
+                    '>' => out.push_str("&gt;"),
+                    '"' => out.push_str("&quot;"),
+                    '\'' => out.push_str("&#39;"),
+                    t => out.push(t),
+                }
+            }
+            Ok(out)
+        })?,
+    )?;
+    // util.random_hex(bytes) for tokens and IDs, from the OS random source
+    module.set(
+        "random_hex",
+        lua.create_function(|_, bytes: Option<usize>| {
+            if bytes.is_some_and(|t| t > MAX_RANDOM_BYTES) {
+                return Err(LuaError::runtime("Too many random bytes requested"));
+            }
+            let mut buf = vec![0u8; bytes.unwrap_or(16)];
+            getrandom::fill(&mut buf).or(Err(LuaError::runtime("Unable to get random bytes")))?;
+            Ok(buf.iter().map(|t| format!("{:02x}", t)).collect::<String>())
+        })?,
+    )?;
+    Ok(module)
+}
+
+/// Reads the attributes of a cookie from an options table, `path` defaults to `/`.
+fn cookie_options(mut cookie: SetCookie, options: Option<Table>) -> Result<SetCookie, LuaError> {
+    cookie.path = Some("/".to_string());
+    let options = match options {
+        Some(options) => options,
+        None => return Ok(cookie),
+    };
+    if let Some(path) = options.get::<Option<String>>("path")? {
+        cookie.path = Some(path);
+    }
+    cookie.domain = options.get("domain")?;
+    if let Some(max_age) = options.get::<Option<i64>>("max_age")? {
+        cookie.max_age = Some(max_age);
+    }
+    if let Some(expires) = options.get::<Option<i64>>("expires")? {
+        cookie.expires = Some(expires);
+    }
+    cookie.secure = options.get::<Option<bool>>("secure")?.unwrap_or(false);
+    cookie.http_only = options.get::<Option<bool>>("http_only")?.unwrap_or(false);
+    cookie.same_site =
+        match options.get::<Option<String>>("same_site")? {
+            Some(same_site) => Some(SameSite::from_str(&same_site).ok_or_else(|| {
+                LuaError::runtime(format!("Invalid SameSite value '{}'", same_site))
+            })?),
+            None => None,
+        };
+    Ok(cookie)
+}
+
+/// Adds a `Set-Cookie` header to a response table, keeping the ones already there.
+fn add_set_cookie(lua: &Lua, response: &Table, cookie: String) -> Result<(), LuaError> {
+    let headers = match response.get::<Option<Table>>("headers")? {
+        Some(headers) => headers,
+        None => {
+            let headers = lua.create_table()?;
+            response.set("headers", &headers)?;
+            headers
+        }
+    };
+    let mut name = "Set-Cookie".to_string();
+    for pair in headers.pairs::<String, Value>() {
+        let (key, _) = pair?;
+        if key.eq_ignore_ascii_case("Set-Cookie") {
+            name = key;
+        }
+    }
+    match headers.get::<Value>(name.as_str())? {
+        Value::Nil => headers.set(name, cookie),
+        Value::Table(cookies) => cookies.push(cookie),
+        first => headers.set(
+            name,
+            lua.create_sequence_from([first, cookie.into_lua(lua)?])?,
+        ),
+    }
+}
+
+fn cookie(lua: &Lua) -> Result<Table, LuaError> {
+    let module = lua.create_table()?;
+    module.set(
+        "build",
+        lua.create_function(
+            |_, (name, value, options): (String, String, Option<Table>)| {
+                cookie_options(SetCookie::new(&name, &value), options)?
+                    .build()
+                    .map_err(LuaError::runtime)
+            },
+        )?,
+    )?;
+    module.set(
+        "set",
+        lua.create_function(
+            |lua, (response, name, value, options): (Table, String, String, Option<Table>)| {
+                let cookie = cookie_options(SetCookie::new(&name, &value), options)?
+                    .build()
+                    .map_err(LuaError::runtime)?;
+                add_set_cookie(lua, &response, cookie)?;
+                Ok(response)
+            },
+        )?,
+    )?;
+    // Only `path` and `domain` of the options matter, they have to match the cookie to delete
+    module.set(
+        "delete",
+        lua.create_function(
+            |lua, (response, name, options): (Table, String, Option<Table>)| {
+                let mut cookie = SetCookie::removal(&name);
+                cookie.path = Some("/".to_string());
+                if let Some(options) = options {
+                    if let Some(path) = options.get::<Option<String>>("path")? {
+                        cookie.path = Some(path);
+                    }
+                    cookie.domain = options.get("domain")?;
+                }
+                let cookie = cookie.build().map_err(LuaError::runtime)?;
+                add_set_cookie(lua, &response, cookie)?;
+                Ok(response)
+            },
+        )?,
+    )?;
+    Ok(module)
+}
@@ -1,8 +1,21 @@
+mod access;
+mod auth;
+mod connections;
+mod cookies;
+mod cors;
 mod errors;
+mod forms;
+mod headers;
 mod logger;
+mod lualib;
+mod mime;
+mod ratelimit;
 mod request;
 mod response;
+mod sandbox;
 mod script;
+mod security;
+mod sessions;
 mod system;
 mod threading;
 
@@ -10,13 +23,25 @@ pub const NAME: &str = env!("CARGO_PKG_NAME");
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 
-use crate::errors::DogError;
+/// Bounds of the pause after accept errors caused by running out of file descriptors.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// Rejections are written by the acceptor itself, which must not wait on slow clients.
+const REJECT_WRITE_TIMEOUT: Duration = Duration::from_millis(50);
+
+use crate::errors::{DogError, HttpCode, NetError};
 use crate::logger::Logger;
-use crate::request::HttpRequest;
+use crate::request::{HttpRequest, RequestLimits};
+use crate::response::HttpResponse;
 use crate::system::System;
 use crate::threading::ThreadPool;
-use std::{env, io::{prelude::*, BufReader}, net::{TcpListener, TcpStream}};
-use std::error::Error;
+use std::{env, io::{prelude::*, BufReader, ErrorKind}, net::{TcpListener, TcpStream}};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::process::{exit, Command};
 use clap::{Arg, ColorChoice};
 
@@ -34,10 +59,30 @@ fn set_thread_panic_hook() {
     }));
 }
 
+/// Why reading a request stopped.
+enum ReadError {
+    /// The client went away, there is nobody to answer
+    Closed,
+    /// The request broke a limit or was malformed
+    Rejected(NetError),
+}
+
+impl From<NetError> for ReadError {
+    fn from(e: NetError) -> Self {
+        ReadError::Rejected(e)
+    }
+}
+
 struct NetDog {
     system: System,
-    listener: TcpListener,
+    listener: Option<TcpListener>,
     pool: ThreadPool,
+    shutdown: Arc<AtomicBool>,
+    /// Rendered once, so rejecting a connection never reads an error page from disk
+    unavailable: HttpResponse,
+    too_many_requests: HttpResponse,
+    /// Reloads changed scripts while it is alive
+    _script_watcher: Option<notify::RecommendedWatcher>,
 }
 
 impl NetDog {
@@ -46,66 +91,319 @@ impl NetDog {
         if system_r.is_err() {
             DogError::__terminate();
         }
-        let system = system_r.unwrap();
+        let mut system = system_r.unwrap();
+        let unavailable = Self::rejection(&mut system, HttpCode::SERVICE_UNAVAILABLE);
+        let too_many_requests = Self::rejection(&mut system, HttpCode::TOO_MANY_REQUESTS);
+
+        let script_watcher = match system.reload_scripts {
+            true => {
+                let watcher_r = system.script_loader.watch();
+                if watcher_r.is_err() {
+                    DogError::__terminate();
+                }
+                watcher_r.ok()
+            }
+            false => None,
+        };
 
         let addr = format!("{}:{:?}", system.ip, system.port);
         println!("Running on http://{}", addr.as_str());
 
         let listener = TcpListener::bind(addr).unwrap();
-        let pool = ThreadPool::new(&system.logger, system.max_cons as usize);
+        let pool = ThreadPool::new(&system.logger, system.max_cons as usize, system.queue_depth);
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        Self::watch_signals(&listener, shutdown.clone());
 
         Self {
             system,
-            listener,
+            listener: Some(listener),
             pool,
+            shutdown,
+            unavailable,
+            too_many_requests,
+            _script_watcher: script_watcher,
+        }
+    }
+
+    /// Sets `shutdown` on SIGINT / SIGTERM and wakes up the acceptor.
+    /// A second signal exits right away.
+    fn watch_signals(listener: &TcpListener, shutdown: Arc<AtomicBool>) {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        for signal in [SIGINT, SIGTERM] {
+            let _ = signal_hook::flag::register_conditional_shutdown(signal, 1, shutdown.clone());
+            let _ = signal_hook::flag::register(signal, shutdown.clone());
         }
+
+        // `accept` blocks, so a connection to ourselves makes the acceptor see the flag
+        let wake_addr = listener.local_addr().ok().map(|addr| match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+            IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+            _ => addr,
+        });
+        thread::spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(100));
+            }
+            if let Some(wake_addr) = wake_addr {
+                let _ = TcpStream::connect_timeout(&wake_addr, Duration::from_secs(1));
+            }
+        });
     }
 
     fn start(&mut self) {
-        for stream in self.listener.incoming() {
+        let mut backoff = Duration::ZERO;
+        // The listener is closed when accepting stops, so new connections are refused while draining
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => return,
+        };
+        for stream in listener.incoming() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
             match stream {
                 Ok(stream) => {
+                    backoff = Duration::ZERO;
+                    let peer = stream.peer_addr().ok().map(|t| t.ip());
+                    if self.pool.is_full() {
+                        let error = NetError::new(HttpCode::SERVICE_UNAVAILABLE, Some(format!("Job queue full ({} waiting)", self.pool.queued())))
+                            .with_header("Retry-After", "1");
+                        self.reject(stream, error);
+                        continue;
+                    }
+                    let guard = match self.system.connections.acquire(peer) {
+                        Ok(guard) => guard,
+                        Err(e) => {
+                            self.reject(stream, e);
+                            continue;
+                        }
+                    };
+                    // Kept to answer the client if the queue filled up since the check above
+                    let spare = stream.try_clone();
                     let sys = self.system.clone();
                     let log = self.system.logger.clone();
-                    self.pool.execute(|| {
-                        NetDog::handle_connection(stream, sys, log);
+                    let queued = self.pool.execute(move || {
+                        NetDog::handle_connection_guarded(stream, sys, log);
+                        drop(guard);
                     });
+                    if !queued {
+                        match spare {
+                            Ok(spare) => {
+                                let error = NetError::new(HttpCode::SERVICE_UNAVAILABLE, Some("Job queue full".to_string()))
+                                    .with_header("Retry-After", "1");
+                                self.reject(spare, error);
+                            }
+                            Err(_e) => self.system.logger.error("Dropped connection, job queue full"),
+                        }
+                    }
                 }
-                Err(_e) => {
-                    self.system.logger.info("Connection failed");
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE)) => {
+                    backoff = (backoff * 2).clamp(ACCEPT_BACKOFF_MIN, ACCEPT_BACKOFF_MAX);
+                    self.system.logger.error(format!("Connection failed ({}), pausing for {:?}", e, backoff).as_str());
+                    thread::sleep(backoff);
+                }
+                Err(e) => {
+                    self.system.logger.info(format!("Connection failed ({})", e).as_str());
                 }
             }
         }
     }
+
+    /// Lets in-flight requests finish within the grace period and runs the scripts' shutdown hooks.
+    fn stop(&mut self) {
+        let grace = self.system.shutdown_grace;
+        self.system.logger.info(format!("Shutting down, waiting up to {:?} for open connections", grace).as_str());
+        let busy = self.pool.shutdown(grace);
+        if busy > 0 {
+            self.system.logger.error(format!("{} workers did not finish in time", busy).as_str());
+        }
+        let panics = self.pool.panics();
+        if panics > 0 {
+            self.system.logger.error(format!("{} requests panicked while running", panics).as_str());
+        }
+        self.system.script_loader.shutdown();
+        self.system.logger.info("Stopped");
+        self.system.logger.flush();
+    }
+
+    fn rejection(system: &mut System, erc: HttpCode) -> HttpResponse {
+        let error = NetError::new(erc, Some(erc.canonical_reason().to_string())).with_header("Retry-After", "1");
+        system.route_error(error)
+    }
+
+    /// Answers a connection right away, without queueing it for a worker.
+    fn reject(&mut self, stream: TcpStream, error: NetError) {
+        let client = stream.peer_addr().map(|t| t.ip().to_string()).unwrap_or_else(|_e| "unknown".to_string());
+        self.system.logger.info(format!("Rejected connection from {}: {}", client, error.details).as_str());
+        // A client which does not read must not stall the acceptor
+        let _ = stream.set_write_timeout(Some(REJECT_WRITE_TIMEOUT));
+        let mut response = match error.erc {
+            HttpCode::TOO_MANY_REQUESTS => self.too_many_requests.clone(),
+            _ => self.unavailable.clone(),
+        };
+        self.system.finalize_response(&mut response);
+        response.send(&self.system.logger, &stream);
+    }
     
-    fn safe_handle_connection(stream: &TcpStream) -> Result<Vec<String>, Box<dyn Error>> {
-        let buf_reader_lines = BufReader::new(stream).lines();
+    fn safe_handle_connection(reader: &mut BufReader<&TcpStream>, limits: &RequestLimits) -> Result<Vec<String>, ReadError> {
+        let deadline = Instant::now() + limits.header_timeout;
         let mut lines = vec![];
 
-        for buf_reader_line in buf_reader_lines {
-            let line = buf_reader_line?;
+        let request_line = Self::read_line(reader, limits.request_line, deadline, HttpCode::URI_TOO_LONG)?;
+        if request_line.is_empty() {
+            return Ok(lines)
+        }
+        lines.push(request_line);
+
+        let mut header_bytes = 0;
+        loop {
+            let max = limits.header_bytes.saturating_sub(header_bytes);
+            let line = Self::read_line(reader, max, deadline, HttpCode::REQUEST_HEADER_FIELDS_TOO_LARGE)?;
             if line.is_empty() {
                 return Ok(lines)
             }
+            header_bytes += line.len();
+            if lines.len() > limits.headers {
+                return Err(NetError::new(
+                    HttpCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    Some("Too many header fields".to_string()),
+                ).into());
+            }
             lines.push(line);
         }
-        
-        Ok(lines)
     }
 
-    fn handle_connection(stream: TcpStream, mut system: System, logger: Logger) {
-        let http_request = Self::safe_handle_connection(&stream);
-        if http_request.is_err() { return; }
-        
-        let request_r = HttpRequest::from_raw(http_request.unwrap());
-        if request_r.is_err() {
-            system
-                .route_error(request_r.unwrap_err())
-                .send(&logger, &stream);
-        } else {
-            system.route(request_r.unwrap()).send(&logger, &stream);
+    /// Applies what is left of the time until `deadline` to the next read.
+    fn set_deadline(stream: &TcpStream, deadline: Instant) -> Result<(), ReadError> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(NetError::new(HttpCode::REQUEST_TIMEOUT, Some("Request timed out".to_string())).into());
+        }
+        stream.set_read_timeout(Some(remaining)).map_err(|_e| ReadError::Closed)
+    }
+
+    fn map_io_error(e: std::io::Error) -> ReadError {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                NetError::new(HttpCode::REQUEST_TIMEOUT, Some("Request timed out".to_string())).into()
+            }
+            _ => ReadError::Closed,
+        }
+    }
+
+    /// Reads a CRLF (or LF) terminated line of at most `max` bytes, answering `too_long` otherwise.
+    fn read_line(reader: &mut BufReader<&TcpStream>, max: usize, deadline: Instant, too_long: HttpCode) -> Result<String, ReadError> {
+        let mut line = vec![];
+        loop {
+            Self::set_deadline(reader.get_ref(), deadline)?;
+            let available = match reader.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Self::map_io_error(e)),
+            };
+            if available.is_empty() {
+                return Err(ReadError::Closed);
+            }
+            let (used, done) = match available.iter().position(|t| *t == b'\n') {
+                Some(idx) => (idx + 1, true),
+                None => (available.len(), false),
+            };
+            line.extend_from_slice(&available[..used]);
+            reader.consume(used);
+
+            if done {
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+            }
+            if line.len() > max {
+                return Err(NetError::new(too_long, Some(format!("Line longer than {} bytes", max))).into());
+            }
+            if done {
+                return String::from_utf8(line).map_err(|_e| {
+                    NetError::new(HttpCode::BAD_REQUEST, Some("Request is not valid UTF-8".to_string())).into()
+                });
+            }
+        }
+    }
+
+    /// Reads a body of `Content-Length` bytes. Chunked bodies are not supported.
+    fn read_body(reader: &mut BufReader<&TcpStream>, request: &HttpRequest, limits: &RequestLimits) -> Result<Vec<u8>, ReadError> {
+        if request.headers.contains("Transfer-Encoding") {
+            return Err(NetError::new(HttpCode::NOT_IMPLEMENTED, Some("Transfer-Encoding is not supported".to_string())).into());
+        }
+        let lengths = request.headers.get_all("Content-Length");
+        let length = match lengths.first() {
+            None => return Ok(vec![]),
+            Some(length) => length.trim().parse::<usize>().ok()
+                .filter(|t| lengths.iter().all(|t1| t1.trim().parse::<usize>().ok() == Some(*t)))
+                .ok_or_else(|| NetError::new(HttpCode::BAD_REQUEST, Some("Invalid Content-Length".to_string())))?,
+        };
+        if length > limits.body {
+            return Err(NetError::new(HttpCode::CONTENT_TOO_LARGE, Some(format!("Body larger than {} bytes", limits.body))).into());
+        }
+        if request.headers.get("Expect").is_some_and(|t| t.eq_ignore_ascii_case("100-continue")) {
+            let mut stream = *reader.get_ref();
+            let _ = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+        }
+
+        let deadline = Instant::now() + limits.body_timeout;
+        let mut body = vec![0u8; length];
+        let mut filled = 0;
+        while filled < length {
+            Self::set_deadline(reader.get_ref(), deadline)?;
+            match reader.read(&mut body[filled..]) {
+                Ok(0) => return Err(ReadError::Closed),
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Self::map_io_error(e)),
+            }
+        }
+        Ok(body)
+    }
+
+    fn read_request(stream: &TcpStream, limits: &RequestLimits) -> Result<HttpRequest, ReadError> {
+        let mut reader = BufReader::new(stream);
+        let lines = Self::safe_handle_connection(&mut reader, limits)?;
+        let mut request = HttpRequest::from_raw(lines)?;
+        request.body = Self::read_body(&mut reader, &request, limits)?;
+        Ok(request)
+    }
+
+    /// Answers with a 500 if handling the connection panics.
+    /// The panic is passed on, so the pool replaces the worker.
+    fn handle_connection_guarded(stream: TcpStream, mut system: System, logger: Logger) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            Self::handle_connection(&stream, &mut system, logger.clone())
+        }));
+        if let Err(payload) = result {
+            let error = DogError::new(&logger, "netpup-panic".to_string(), "Handling a request panicked".to_string());
+            let mut response = system.netpup_error(error);
+            system.finalize_response(&mut response);
+            response.send(&logger, &stream);
+            panic::resume_unwind(payload);
         }
     }
+
+    fn handle_connection(stream: &TcpStream, system: &mut System, mut logger: Logger) {
+        let peer = stream.peer_addr().ok().map(|t| t.ip());
+        let mut response = match Self::read_request(stream, &system.limits) {
+            Ok(mut request) => {
+                request.client_ip = peer;
+                system.route(request)
+            }
+            Err(ReadError::Closed) => return,
+            Err(ReadError::Rejected(e)) => {
+                let client = peer.map(|t| t.to_string()).unwrap_or_else(|| "unknown".to_string());
+                logger.info(format!("Rejected request from {}: {} ({})", client, e.details, e.erc.to_num()).as_str());
+                system.route_error(e)
+            }
+        };
+        system.finalize_response(&mut response);
+        response.send(&logger, stream);
+    }
 }
 
 fn _netpup_start(config_path: String) {
@@ -113,6 +411,7 @@ fn _netpup_start(config_path: String) {
     
     let mut netpup = NetDog::new(config_path);
     netpup.start();
+    netpup.stop();
 }
 
 fn update_and_restart() {
@@ -192,3 +491,162 @@ fn main() {
         _netpup_start(config_path.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Feeds `raw` to the request reader through a local connection.
+    /// The client stays connected until the reader is done.
+    fn read(raw: &[u8], limits: &RequestLimits) -> Result<HttpRequest, ReadError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let raw = raw.to_vec();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let _ = stream.write_all(&raw);
+            let _ = done_rx.recv();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let result = NetDog::read_request(&stream, limits);
+        drop(done_tx);
+        client.join().unwrap();
+        result
+    }
+
+    /// The status code a read was rejected with, `None` if the request was read.
+    fn status(raw: &[u8], limits: &RequestLimits) -> Option<u16> {
+        match read(raw, limits) {
+            Ok(_) => None,
+            Err(ReadError::Rejected(e)) => Some(e.erc.to_num()),
+            Err(ReadError::Closed) => panic!("connection closed"),
+        }
+    }
+
+    fn limits() -> RequestLimits {
+        RequestLimits {
+            request_line: 32,
+            header_bytes: 64,
+            headers: 3,
+            body: 16,
+            header_timeout: Duration::from_millis(300),
+            body_timeout: Duration::from_millis(300),
+            ..RequestLimits::default()
+        }
+    }
+
+    fn request_line(len: usize) -> String {
+        let path = "a".repeat(len - "GET / HTTP/1.1".len());
+        format!("GET /{} HTTP/1.1", path)
+    }
+
+    #[test]
+    fn request_line_limit() {
+        let limits = limits();
+        let exact = format!("{}\r\n\r\n", request_line(32));
+        assert_eq!(status(exact.as_bytes(), &limits), None);
+        let over = format!("{}\r\n\r\n", request_line(33));
+        assert_eq!(status(over.as_bytes(), &limits), Some(414));
+        // The limit holds before the line ends
+        let unterminated = "GET /".to_string() + &"a".repeat(4096);
+        assert_eq!(status(unterminated.as_bytes(), &limits), Some(414));
+    }
+
+    #[test]
+    fn header_bytes_limit() {
+        let limits = limits();
+        // Two lines of 32 bytes each, without their line endings
+        let header = format!("X-A: {}", "a".repeat(27));
+        let exact = format!("GET / HTTP/1.1\r\n{}\r\n{}\r\n\r\n", header, header);
+        assert_eq!(status(exact.as_bytes(), &limits), None);
+        let over = format!("GET / HTTP/1.1\r\n{}\r\n{}a\r\n\r\n", header, header);
+        assert_eq!(status(over.as_bytes(), &limits), Some(431));
+        let single = format!("GET / HTTP/1.1\r\nX-A: {}\r\n\r\n", "a".repeat(4096));
+        assert_eq!(status(single.as_bytes(), &limits), Some(431));
+    }
+
+    #[test]
+    fn header_count_limit() {
+        let limits = limits();
+        let exact = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert_eq!(status(exact.as_bytes(), &limits), None);
+        let over = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n";
+        assert_eq!(status(over.as_bytes(), &limits), Some(431));
+    }
+
+    #[test]
+    fn body_limit() {
+        let limits = limits();
+        let exact = format!(
+            "POST / HTTP/1.1\r\nContent-Length: 16\r\n\r\n{}",
+            "b".repeat(16)
+        );
+        match read(exact.as_bytes(), &limits) {
+            Ok(request) => assert_eq!(request.body, "b".repeat(16).into_bytes()),
+            Err(_) => panic!("body at the limit was rejected"),
+        }
+        let over = format!(
+            "POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n{}",
+            "b".repeat(17)
+        );
+        assert_eq!(status(over.as_bytes(), &limits), Some(413));
+        let invalid = "POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab";
+        assert_eq!(status(invalid.as_bytes(), &limits), Some(400));
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(status(chunked.as_bytes(), &limits), Some(501));
+    }
+
+    #[test]
+    fn timeouts() {
+        let limits = limits();
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: x", &limits), Some(408));
+        let partial = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhalf";
+        assert_eq!(status(partial.as_bytes(), &limits), Some(408));
+    }
+
+    #[test]
+    fn closed_connections_are_not_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET / HT").unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        client.join().unwrap();
+        assert!(matches!(
+            NetDog::read_request(&stream, &limits()),
+            Err(ReadError::Closed)
+        ));
+    }
+
+    #[test]
+    fn rejections_are_rendered_once() {
+        let dir = env::temp_dir().join(format!("netpup-main-reject-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("busy.html");
+        std::fs::write(&page, "Busy, try again").unwrap();
+        let config = dir.join("config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                "ip = \"127.0.0.1\"\n[routes]\n[errors.503]\npath = {:?}\n",
+                page.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        let mut system = System::from_file(config.to_string_lossy().to_string()).unwrap();
+        let unavailable = NetDog::rejection(&mut system, HttpCode::SERVICE_UNAVAILABLE);
+
+        // The page is not read again when a connection is rejected
+        std::fs::remove_dir_all(&dir).unwrap();
+        let raw = String::from_utf8(unavailable.clone().make()).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(raw.lines().any(|t| t.trim_end() == "Retry-After: 1"));
+        assert!(raw.ends_with("Busy, try again"));
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const DEFAULT_MIME: &str = "application/octet-stream";
+
+const BUILTIN_TYPES: &[(&str, &str)] = &[
+    // Text & documents
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("xhtml", "application/xhtml+xml"),
+    ("css", "text/css"),
+    ("txt", "text/plain"),
+    ("text", "text/plain"),
+    ("log", "text/plain"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("ics", "text/calendar"),
+    ("vcf", "text/vcard"),
+    ("rtf", "application/rtf"),
+    ("xml", "application/xml"),
+    ("xsl", "application/xml"),
+    ("rss", "application/rss+xml"),
+    ("atom", "application/atom+xml"),
+    ("pdf", "application/pdf"),
+    ("epub", "application/epub+zip"),
+    ("doc", "application/msword"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    ("xls", "application/vnd.ms-excel"),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+    // Scripts & data
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("cjs", "text/javascript"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("toml", "application/toml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("lua", "text/x-lua"),
+    ("sh", "application/x-sh"),
+    // Images
+    ("png", "image/png"),
+    ("apng", "image/apng"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("jfif", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("bmp", "image/bmp"),
+    ("svg", "image/svg+xml"),
+    ("svgz", "image/svg+xml"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("ico", "image/x-icon"),
+    ("cur", "image/x-icon"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("jxl", "image/jxl"),
+    // Fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    // Audio
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("flac", "audio/flac"),
+    ("aac", "audio/aac"),
+    ("m4a", "audio/mp4"),
+    ("mid", "audio/midi"),
+    ("midi", "audio/midi"),
+    ("weba", "audio/webm"),
+    // Video
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("avi", "video/x-msvideo"),
+    ("mov", "video/quicktime"),
+    ("mkv", "video/x-matroska"),
+    ("mpeg", "video/mpeg"),
+    ("mpg", "video/mpeg"),
+    ("ts", "video/mp2t"),
+    ("3gp", "video/3gpp"),
+    // Archives
+    ("zip", "application/zip"),
+    ("tar", "application/x-tar"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+    ("jar", "application/java-archive"),
+    // Binaries
+    ("bin", DEFAULT_MIME),
+    ("exe", "application/vnd.microsoft.portable-executable"),
+    ("dmg", "application/x-apple-diskimage"),
+    ("iso", "application/x-iso9660-image"),
+    ("apk", "application/vnd.android.package-archive"),
+    ("deb", "application/vnd.debian.binary-package"),
+];
+
+/// Magic byte prefixes used to sniff extensionless files: (offset, signature, type).
+const MAGIC_BYTES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"\x00\x00\x01\x00", "image/x-icon"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"wOFF", "font/woff"),
+    (0, b"wOF2", "font/woff2"),
+    (0, b"\x00asm", "application/wasm"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (257, b"ustar", "application/x-tar"),
+];
+
+/// Form types of RIFF files, which start with `RIFF`, the size and the form type.
+const RIFF_TYPES: &[(&[u8], &str)] = &[
+    (b"WEBP", "image/webp"),
+    (b"WAVE", "audio/wav"),
+    (b"AVI ", "video/x-msvideo"),
+];
+
+/// Major brands of ISO base media files, which start with the box size and `ftyp`.
+const FTYP_BRANDS: &[(&[u8], &str)] = &[
+    (b"avif", "image/avif"),
+    (b"avis", "image/avif"),
+    (b"heic", "image/heic"),
+    (b"heix", "image/heic"),
+    (b"hevc", "image/heic"),
+    (b"hevx", "image/heic"),
+    (b"heim", "image/heic"),
+    (b"heis", "image/heic"),
+    (b"mif1", "image/heif"),
+    (b"msf1", "image/heif"),
+    (b"M4A ", "audio/mp4"),
+    (b"qt  ", "video/quicktime"),
+    (b"3gp4", "video/3gpp"),
+    (b"3gp5", "video/3gpp"),
+    (b"3gp6", "video/3gpp"),
+    (b"isom", "video/mp4"),
+    (b"iso2", "video/mp4"),
+    (b"mp41", "video/mp4"),
+    (b"mp42", "video/mp4"),
+    (b"avc1", "video/mp4"),
+    (b"dash", "video/mp4"),
+    (b"M4V ", "video/mp4"),
+];
+
+/// RIFF and ISO base media files only tell their type at offset 8, after the container header.
+fn container_type(content: &[u8]) -> Option<&'static str> {
+    let kind = content.get(8..12)?;
+    let table = if content.starts_with(b"RIFF") {
+        RIFF_TYPES
+    } else if content.get(4..8) == Some(b"ftyp") {
+        FTYP_BRANDS
+    } else {
+        return None;
+    };
+    table
+        .iter()
+        .find(|(t, _)| *t == kind)
+        .map(|(_, mime)| *mime)
+}
+
+/// Bitmaps only start with `BM`, which plain text may do as well,
+/// so the file size, reserved bytes and info header size are checked too.
+fn is_bmp(content: &[u8]) -> bool {
+    let le_u32 = |pos: usize| {
+        content
+            .get(pos..pos + 4)
+            .map(|t| u32::from_le_bytes([t[0], t[1], t[2], t[3]]))
+    };
+    content.starts_with(b"BM")
+        && le_u32(2) == u32::try_from(content.len()).ok()
+        && le_u32(6) == Some(0)
+        && matches!(le_u32(14), Some(12 | 40 | 52 | 56 | 64 | 108 | 124))
+}
+
+/// Maps file extensions to MIME types.
+/// Starts out with a built-in table, which can be extended or overridden from the config.
+#[derive(Clone, Debug)]
+pub struct MimeRegistry {
+    types: HashMap<String, String>,
+    sniff: bool,
+}
+
+impl MimeRegistry {
+    pub fn new(overrides: HashMap<String, String>, sniff: bool) -> Self {
+        let mut types: HashMap<String, String> = BUILTIN_TYPES
+            .iter()
+            .map(|(ext, mime)| (ext.to_string(), mime.to_string()))
+            .collect();
+        for (ext, mime) in overrides {
+            types.insert(ext.trim_start_matches('.').to_lowercase(), mime);
+        }
+        Self { types, sniff }
+    }
+
+    /// Adds `charset=utf-8` to textual types which do not specify a charset.
+    fn with_charset(mime: &str) -> String {
+        let essence = mime.split(';').next().unwrap_or("").trim();
+        let is_text = essence.starts_with("text/")
+            || essence.ends_with("+json")
+            || essence.ends_with("+xml")
+            || matches!(
+                essence,
+                "application/json"
+                    | "application/xml"
+                    | "application/javascript"
+                    | "application/toml"
+                    | "application/yaml"
+            );
+        if is_text && !mime.to_lowercase().contains("charset=") {
+            format!("{}; charset=utf-8", mime)
+        } else {
+            mime.to_string()
+        }
+    }
+
+    pub fn lookup_ext(&self, ext: &str) -> Option<String> {
+        self.types
+            .get(&ext.trim_start_matches('.').to_lowercase())
+            .map(|mime| Self::with_charset(mime))
+    }
+
+    /// Resolves a type given by a script or the config,
+    /// which is either a full MIME type (`text/html`) or a file extension (`html`).
+    pub fn resolve(&self, ty: &str) -> String {
+        if ty.is_empty() || ty.contains('/') {
+            ty.to_string()
+        } else {
+            self.lookup_ext(ty)
+                .unwrap_or_else(|| DEFAULT_MIME.to_string())
+        }
+    }
+
+    /// Looks up the type of a file by its last extension (`jquery.min.js` -> `js`).
+    /// Extensionless files are sniffed by their content if sniffing is enabled.
+    pub fn lookup_file(&self, file_name: &str, content: &[u8]) -> String {
+        match Path::new(file_name).extension().and_then(|t| t.to_str()) {
+            Some(ext) => self
+                .lookup_ext(ext)
+                .unwrap_or_else(|| DEFAULT_MIME.to_string()),
+            None if self.sniff => Self::sniff(content),
+            None => DEFAULT_MIME.to_string(),
+        }
+    }
+
+    fn sniff(content: &[u8]) -> String {
+        if is_bmp(content) {
+            return "image/bmp".to_string();
+        }
+        if let Some(mime) = container_type(content) {
+            return mime.to_string();
+        }
+        for (offset, magic, mime) in MAGIC_BYTES {
+            if content.len() >= offset + magic.len()
+                && &content[*offset..offset + magic.len()] == *magic
+            {
+                return mime.to_string();
+            }
+        }
+
+        let head = &content[..content.len().min(1024)];
+        let text = match std::str::from_utf8(head) {
+            Ok(text) => text,
+            // The cut may have split a multibyte character
+            Err(e) if e.error_len().is_none() => {
+                std::str::from_utf8(&head[..e.valid_up_to()]).unwrap()
+            }
+            Err(_) => return DEFAULT_MIME.to_string(),
+        };
+        if text.contains('\0') {
+            return DEFAULT_MIME.to_string();
+        }
+        let start = text.trim_start().to_lowercase();
+        let mime = if start.starts_with("<!doctype html") || start.starts_with("<html") {
+            "text/html"
+        } else if start.starts_with("<svg") {
+            "image/svg+xml"
+        } else if start.starts_with("<?xml") {
+            "application/xml"
+        } else {
+            "text/plain"
+        };
+        Self::with_charset(mime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> MimeRegistry {
+        MimeRegistry::new(HashMap::new(), true)
+    }
+
+    #[test]
+    fn lookup_uses_last_extension() {
+        let mime = registry();
+        assert_eq!(
+            mime.lookup_file("jquery.min.js", b""),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(mime.lookup_file("archive.tar.gz", b""), "application/gzip");
+        assert_eq!(
+            mime.lookup_file("static/app.v2.CSS", b""),
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(mime.lookup_file("data.unknown", b"<html>"), DEFAULT_MIME);
+        assert_eq!(
+            mime.lookup_file(".hidden", b"plain"),
+            "text/plain; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn overrides_extend_and_replace() {
+        let overrides = HashMap::from([
+            (".TS".to_string(), "text/typescript".to_string()),
+            ("foo".to_string(), "application/x-foo".to_string()),
+        ]);
+        let mime = MimeRegistry::new(overrides, false);
+        assert_eq!(
+            mime.lookup_ext("ts").as_deref(),
+            Some("text/typescript; charset=utf-8")
+        );
+        assert_eq!(
+            mime.lookup_ext(".foo").as_deref(),
+            Some("application/x-foo")
+        );
+        assert_eq!(mime.lookup_ext("png").as_deref(), Some("image/png"));
+    }
+
+    #[test]
+    fn resolve_accepts_types_and_extensions() {
+        let mime = registry();
+        assert_eq!(mime.resolve("text/plain"), "text/plain");
+        assert_eq!(mime.resolve("json"), "application/json; charset=utf-8");
+        assert_eq!(mime.resolve("nope"), DEFAULT_MIME);
+        assert_eq!(
+            MimeRegistry::with_charset("text/html; charset=latin1"),
+            "text/html; charset=latin1"
+        );
+    }
+
+    #[test]
+    fn sniffs_extensionless_files() {
+        let mime = registry();
+        assert_eq!(
+            mime.lookup_file("logo", b"\x89PNG\r\n\x1a\n...."),
+            "image/png"
+        );
+        assert_eq!(mime.lookup_file("doc", b"%PDF-1.7"), "application/pdf");
+        assert_eq!(
+            mime.lookup_file("page", b"  <!DOCTYPE html><p>"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            mime.lookup_file("notes", "héllo".as_bytes()),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(mime.lookup_file("blob", b"\x00\x01\x02\xff"), DEFAULT_MIME);
+
+        let off = MimeRegistry::new(HashMap::new(), false);
+        assert_eq!(off.lookup_file("logo", b"\x89PNG\r\n\x1a\n"), DEFAULT_MIME);
+    }
+
+    #[test]
+    fn sniffs_containers_by_their_type() {
+        let mime = registry();
+        assert_eq!(
+            mime.lookup_file("image", b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            "image/webp"
+        );
+        assert_eq!(
+            mime.lookup_file("sound", b"RIFF\x24\x00\x00\x00WAVEfmt "),
+            "audio/wav"
+        );
+        assert_eq!(
+            mime.lookup_file("movie", b"RIFF\x24\x00\x00\x00AVI LIST"),
+            "video/x-msvideo"
+        );
+        // The form type alone is not enough
+        assert_eq!(
+            mime.lookup_file("notes", b"Reading WEBP specs"),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            mime.lookup_file("blob", b"\x00\x01\x02\x03\x04\x05\x06\x07WAVE\xff"),
+            DEFAULT_MIME
+        );
+
+        let ftyp = |brand: &[u8]| {
+            let mut content = b"\x00\x00\x00\x18ftyp".to_vec();
+            content.extend_from_slice(brand);
+            content.extend_from_slice(b"\x00\x00\x00\x00mif1\xff");
+            mime.lookup_file("media", &content)
+        };
+        assert_eq!(ftyp(b"avif"), "image/avif");
+        assert_eq!(ftyp(b"heic"), "image/heic");
+        assert_eq!(ftyp(b"mif1"), "image/heif");
+        assert_eq!(ftyp(b"M4A "), "audio/mp4");
+        assert_eq!(ftyp(b"qt  "), "video/quicktime");
+        assert_eq!(ftyp(b"3gp5"), "video/3gpp");
+        assert_eq!(ftyp(b"isom"), "video/mp4");
+        assert_eq!(ftyp(b"mp42"), "video/mp4");
+        assert_eq!(ftyp(b"crx "), DEFAULT_MIME);
+    }
+
+    #[test]
+    fn bitmaps_need_a_valid_header() {
+        let mime = registry();
+        let mut bmp = b"BM".to_vec();
+        bmp.extend_from_slice(&58u32.to_le_bytes());
+        bmp.extend_from_slice(&[0; 4]);
+        bmp.extend_from_slice(&54u32.to_le_bytes());
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.resize(58, 0);
+        assert_eq!(mime.lookup_file("image", &bmp), "image/bmp");
+
+        // A wrong size field is no bitmap
+        let mut truncated = bmp.clone();
+        truncated.truncate(57);
+        assert_eq!(mime.lookup_file("image", &truncated), DEFAULT_MIME);
+        assert_eq!(
+            mime.lookup_file("notes", b"BMW service notes, due in March"),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            mime.lookup_file("short", b"BM"),
+            "text/plain; charset=utf-8"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use crate::errors::{DogError, DogResult, HttpCode, NetError, NetResult};
+use crate::headers::Headers;
+use crate::logger::Logger;
+use crate::request::HttpRequest;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use toml::{Table, Value};
+
+/// How often idle buckets are swept from memory.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What requests are grouped by.
+#[derive(Clone, Debug)]
+enum RateKey {
+    Ip,
+    User,
+    Header(String),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    last_sweep: Instant,
+}
+
+/// Token bucket rate limiter, shared between all connections.
+/// Each client gets `burst` tokens, which refill at `requests` per `per` seconds.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    requests: u32,
+    per: u64,
+    burst: f64,
+    key: RateKey,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    /// Loads a `rate_limit = { requests = 100, per = 60, burst = 20, key = "ip" }` setting.
+    pub fn new(logger: &Logger, t: &Table) -> DogResult<Self> {
+        let ill_formatted = |key: &str| {
+            DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                format!("Ill formatted key 'rate_limit.{}'", key),
+            )
+        };
+        let get_positive = |key: &str, default: Option<i64>| -> DogResult<i64> {
+            match t.get(key) {
+                Some(Value::Integer(value)) if *value > 0 => Ok(*value),
+                None => default.ok_or_else(|| ill_formatted(key)),
+                Some(_) => Err(ill_formatted(key)),
+            }
+        };
+        let requests = get_positive("requests", None)?;
+        let per = get_positive("per", Some(60))?;
+        let burst = get_positive("burst", Some(requests))?;
+        let key = match t.get("key").map(|t1| t1.as_str()) {
+            None | Some(Some("ip")) => RateKey::Ip,
+            Some(Some("user")) => RateKey::User,
+            Some(Some(key)) if key.starts_with("header:") => {
+                RateKey::Header(key["header:".len()..].trim().to_string())
+            }
+            Some(_) => return Err(ill_formatted("key")),
+        };
+
+        Ok(Self {
+            requests: u32::try_from(requests).map_err(|_e| ill_formatted("requests"))?,
+            per: per as u64,
+            burst: burst as f64,
+            key,
+            buckets: Arc::new(Mutex::new(Buckets {
+                map: HashMap::new(),
+                last_sweep: Instant::now(),
+            })),
+        })
+    }
+
+    /// Whether clients are grouped by their user, which is only known after authentication.
+    pub fn is_keyed_on_user(&self) -> bool {
+        matches!(self.key, RateKey::User)
+    }
+
+    /// Tokens regained per second.
+    fn rate(&self) -> f64 {
+        self.requests as f64 / self.per as f64
+    }
+
+    /// Clients without a user or the configured header are grouped by their address.
+    fn client_key(&self, req: &HttpRequest) -> String {
+        let fallback = || {
+            req.client_ip
+                .map(|t| t.to_string())
+                .unwrap_or_else(|| "unknown".to_string())
+        };
+        match &self.key {
+            RateKey::Ip => fallback(),
+            RateKey::User => req
+                .user
+                .as_ref()
+                .map(|t| format!("user:{}", t))
+                .unwrap_or_else(fallback),
+            RateKey::Header(name) => req
+                .headers
+                .get(name)
+                .map(|t| format!("header:{}", t))
+                .unwrap_or_else(fallback),
+        }
+    }
+
+    /// Takes a token for the request, answering with the `RateLimit-*` headers
+    /// for the response or a 429 error once the bucket is empty.
+    pub fn check(&self, req: &HttpRequest) -> NetResult<Headers> {
+        let key = self.client_key(req);
+        let rate = self.rate();
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if now.duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            // Buckets which refilled completely are equal to new ones
+            let burst = self.burst;
+            buckets.map.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+            buckets.last_sweep = now;
+        }
+
+        let bucket = buckets.map.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate)
+            .min(self.burst);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let tokens = bucket.tokens;
+        drop(buckets);
+
+        let mut headers = Headers::new();
+        let reset = ((self.burst - tokens) / rate).ceil() as u64;
+        let _ = headers.append("RateLimit-Limit", &(self.burst as u64).to_string());
+        let _ = headers.append("RateLimit-Remaining", &(tokens.floor() as u64).to_string());
+        let _ = headers.append("RateLimit-Reset", &reset.to_string());
+        // The policy describes the same quota as the limit, `burst` requests per time to refill them
+        let window = (self.burst / rate).ceil() as u64;
+        let _ = headers.append(
+            "RateLimit-Policy",
+            &format!("{};w={}", self.burst as u64, window),
+        );
+        if allowed {
+            return Ok(headers);
+        }
+
+        let retry_after = ((1.0 - tokens) / rate).ceil().max(1.0) as u64;
+        let mut error = NetError::new(
+            HttpCode::TOO_MANY_REQUESTS,
+            Some("Too many requests".to_string()),
+        )
+        .with_header("Retry-After", &retry_after.to_string());
+        error.headers.extend(&headers);
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(settings: &str) -> DogResult<RateLimiter> {
+        let t: Table = settings.parse().unwrap();
+        RateLimiter::new(&Logger::new(false, None).unwrap(), &t)
+    }
+
+    fn request(ip: &str) -> HttpRequest {
+        let mut req = HttpRequest::from_raw(vec!["GET / HTTP/1.1".to_string()]).unwrap();
+        req.client_ip = Some(ip.parse().unwrap());
+        req
+    }
+
+    fn remaining(headers: &Headers) -> u64 {
+        headers.get("RateLimit-Remaining").unwrap().parse().unwrap()
+    }
+
+    /// Moves the last refill of every bucket `secs` into the past.
+    fn rewind(limiter: &RateLimiter, secs: u64) {
+        let mut buckets = limiter.buckets.lock().unwrap();
+        for bucket in buckets.map.values_mut() {
+            bucket.updated -= Duration::from_secs(secs);
+        }
+    }
+
+    #[test]
+    fn burst_then_reject() {
+        let limiter = limiter("requests = 10\nper = 60\nburst = 3").unwrap();
+        let req = request("10.0.0.1");
+        for expected in [2, 1, 0] {
+            let headers = limiter.check(&req).unwrap();
+            assert_eq!(remaining(&headers), expected);
+            assert_eq!(headers.get("RateLimit-Limit"), Some("3"));
+            assert_eq!(headers.get("RateLimit-Policy"), Some("3;w=18"));
+        }
+        let error = limiter.check(&req).unwrap_err();
+        assert_eq!(error.erc, HttpCode::TOO_MANY_REQUESTS);
+        // One token takes 6 seconds at 10 requests per minute
+        assert_eq!(error.headers.get("Retry-After"), Some("6"));
+        assert_eq!(error.headers.get("RateLimit-Remaining"), Some("0"));
+        assert_eq!(error.headers.get("RateLimit-Reset"), Some("18"));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let limiter = limiter("requests = 10\nper = 60\nburst = 3").unwrap();
+        let req = request("10.0.0.1");
+        for _ in 0..3 {
+            limiter.check(&req).unwrap();
+        }
+        assert!(limiter.check(&req).is_err());
+
+        rewind(&limiter, 12);
+        assert_eq!(remaining(&limiter.check(&req).unwrap()), 1);
+        assert_eq!(remaining(&limiter.check(&req).unwrap()), 0);
+        assert!(limiter.check(&req).is_err());
+
+        // Refilling stops at the burst size
+        rewind(&limiter, 60);
+        assert_eq!(remaining(&limiter.check(&req).unwrap()), 2);
+    }
+
+    #[test]
+    fn burst_defaults_to_requests() {
+        let limiter = limiter("requests = 2").unwrap();
+        let req = request("10.0.0.1");
+        let headers = limiter.check(&req).unwrap();
+        assert_eq!(headers.get("RateLimit-Limit"), Some("2"));
+        assert_eq!(headers.get("RateLimit-Policy"), Some("2;w=60"));
+        limiter.check(&req).unwrap();
+        assert!(limiter.check(&req).is_err());
+    }
+
+    #[test]
+    fn clients_have_separate_buckets() {
+        let limiter = limiter("requests = 1\nkey = \"header: X-Api-Key\"").unwrap();
+        let mut a = request("10.0.0.1");
+        a.headers.append("X-Api-Key", "a").unwrap();
+        let mut b = request("10.0.0.1");
+        b.headers.append("x-api-key", "b").unwrap();
+        assert!(limiter.check(&a).is_ok());
+        assert!(limiter.check(&a).is_err());
+        assert!(limiter.check(&b).is_ok());
+        // Without the header the address is used
+        assert!(limiter.check(&request("10.0.0.1")).is_ok());
+        assert!(limiter.check(&request("10.0.0.2")).is_ok());
+        assert!(limiter.check(&request("10.0.0.1")).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(limiter("per = 60").is_err());
+        assert!(limiter("requests = 0").is_err());
+        assert!(limiter("requests = 10\nper = -1").is_err());
+        assert!(limiter("requests = 10\nburst = \"5\"").is_err());
+        assert!(limiter("requests = 10\nkey = \"cookie\"").is_err());
+        assert!(limiter("requests = 10\nkey = \"user\"").is_ok());
+    }
+}
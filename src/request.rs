@@ -1,12 +1,15 @@
-use crate::errors::HttpCode::BadRequest;
-use crate::errors::{NetError, NetResult};
+use crate::errors::{HttpCode, NetError, NetResult};
+use crate::forms::FormLimits;
+use crate::headers::Headers;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Methods {
     GET,
     POST,
+    OPTIONS,
 }
 
 impl Methods {
@@ -14,6 +17,7 @@ impl Methods {
         match s.to_uppercase().as_str() {
             "GET" => Ok(Methods::GET),
             "POST" => Ok(Methods::POST),
+            "OPTIONS" => Ok(Methods::OPTIONS),
             _ => Err(()),
         }
     }
@@ -28,19 +32,48 @@ impl Methods {
     }
 }
 
-pub type Headers = HashMap<String, String>;
+/// Limits on what clients may send, configured in `[limits]`.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLimits {
+    /// Longest accepted request line in bytes (414 otherwise)
+    pub request_line: usize,
+    /// Total size of all header lines in bytes (431 otherwise)
+    pub header_bytes: usize,
+    /// Number of header lines (431 otherwise)
+    pub headers: usize,
+    /// Body size in bytes (413 otherwise)
+    pub body: usize,
+    /// Time to receive the request line and headers (408 otherwise)
+    pub header_timeout: Duration,
+    /// Time to receive the body (408 otherwise)
+    pub body_timeout: Duration,
+    /// Forms parsed for scripts
+    pub form: FormLimits,
+}
 
-fn split_once(in_string: &str) -> Result<(&str, &str), NetError> {
-    let mut splitter = in_string.splitn(2, ": ");
-    let first = splitter.next();
-    let second = splitter.next();
-    if first.is_none() || second.is_none() {
-        Err(NetError::new(BadRequest, None))
-    } else {
-        Ok((first.unwrap(), second.unwrap()))
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            request_line: 8 * 1024,
+            header_bytes: 16 * 1024,
+            headers: 100,
+            body: 1024 * 1024,
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            form: FormLimits::default(),
+        }
     }
 }
 
+fn split_once(in_string: &str) -> Result<(&str, &str), NetError> {
+    in_string.split_once(':').ok_or_else(|| {
+        NetError::new(
+            HttpCode::BAD_REQUEST,
+            Some("Malformed header line".to_string()),
+        )
+    })
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HttpRequest {
     pub method: Methods,
@@ -49,6 +82,10 @@ pub struct HttpRequest {
     pub headers: Headers,
     pub body: Vec<u8>,
     pub host: Option<String>,
+    pub user: Option<String>,
+    pub claims: Option<serde_json::Value>,
+    pub client_ip: Option<IpAddr>,
+    pub csp_nonce: Option<String>,
 }
 
 impl HttpRequest {
@@ -61,7 +98,7 @@ impl HttpRequest {
     */
 
     pub fn format(&self) -> String {
-        format!(
+        let mut formatted = format!(
             "{:?} {} ({})",
             self.method,
             self.path,
@@ -69,7 +106,14 @@ impl HttpRequest {
                 .clone()
                 .or_else(|| { Some("None".to_string()) })
                 .unwrap()
-        )
+        );
+        if let Some(client_ip) = &self.client_ip {
+            formatted += format!(" from {}", client_ip).as_str();
+        }
+        if let Some(user) = &self.user {
+            formatted += format!(" [{}]", user).as_str();
+        }
+        formatted
     }
 
     pub fn mk_headers(lns: Vec<String>) -> NetResult<Headers> {
@@ -77,7 +121,7 @@ impl HttpRequest {
 
         for ln in lns {
             let x = split_once(&ln)?;
-            hsm.insert(x.0.to_string(), x.1.to_string());
+            hsm.append(x.0, x.1)?;
         }
 
         Ok(hsm)
@@ -85,17 +129,17 @@ impl HttpRequest {
 
     pub fn from_raw(mut req_lines: Vec<String>) -> NetResult<Self> {
         if (&req_lines).is_empty() {
-            return Err(NetError::new(BadRequest, None));
+            return Err(NetError::new(HttpCode::BAD_REQUEST, None));
         }
         let head_line = &(&req_lines)[0];
         let head_line_v = head_line.split(" ").collect::<Vec<_>>();
         if head_line_v.len() != 3 {
-            return Err(NetError::new(BadRequest, None));
+            return Err(NetError::new(HttpCode::BAD_REQUEST, None));
         }
 
         let method_r = Methods::from_str(head_line_v[0].to_uppercase().as_str());
         if method_r.is_err() {
-            return Err(NetError::new(BadRequest, None));
+            return Err(NetError::new(HttpCode::BAD_REQUEST, None));
         }
         let method = method_r.unwrap();
 
@@ -105,7 +149,7 @@ impl HttpRequest {
         req_lines.remove(0);
 
         let headers = Self::mk_headers(req_lines)?;
-        let host = headers.get("Host").cloned();
+        let host = headers.get("Host").map(|t| t.to_string());
 
         Ok(Self {
             method,
@@ -114,6 +158,10 @@ impl HttpRequest {
             headers,
             body: vec![],
             host,
+            user: None,
+            claims: None,
+            client_ip: None,
+            csp_nonce: None,
         })
     }
 }
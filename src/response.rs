@@ -1,115 +1,15 @@
-use crate::errors::{DogError, HttpCode, NetError};
+use crate::errors::{DogError, HttpCode, NetError, NetResult};
+use crate::headers::Headers;
 use crate::logger::Logger;
-use crate::request::Headers;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use std::io::Write;
 use std::net::TcpStream;
 
-#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
-pub enum ContentType {
-    HTML,
-    JSON,
-    XML,
-    PLAIN,
-    CSS,
-    JAVASCRIPT,
-    JPEG,
-    PNG,
-    GIF,
-    BMP,
-    SVG,
-    WEBP,
-    MP3,
-    MP4,
-    WAV,
-    OGG,
-    AVI,
-    PDF,
-    ZIP,
-    TAR,
-    GZIP,
-    BZIP2,
-    WEBM,
-    ICO,
-    NONE,
-    UNKNOWN,
-}
-
-impl ContentType {
-    pub fn to_string(&self) -> String {
-        match self {
-            ContentType::HTML => "text/html",
-            ContentType::JSON => "application/json",
-            ContentType::XML => "application/xml",
-            ContentType::PLAIN => "text/plain",
-            ContentType::CSS => "text/css",
-            ContentType::JAVASCRIPT => "application/javascript",
-            ContentType::JPEG => "image/jpeg",
-            ContentType::PNG => "image/png",
-            ContentType::GIF => "image/gif",
-            ContentType::BMP => "image/bmp",
-            ContentType::SVG => "image/svg+xml",
-            ContentType::WEBP => "image/webp",
-            ContentType::MP3 => "audio/mpeg",
-            ContentType::MP4 => "video/mp4",
-            ContentType::WAV => "audio/wav",
-            ContentType::OGG => "audio/ogg",
-            ContentType::AVI => "video/x-msvideo",
-            ContentType::PDF => "application/pdf",
-            ContentType::ZIP => "application/zip",
-            ContentType::TAR => "application/x-tar",
-            ContentType::GZIP => "application/gzip",
-            ContentType::BZIP2 => "application/x-bzip2",
-            ContentType::WEBM => "video/webm",
-            ContentType::ICO => "image/x-icon",
-            ContentType::NONE => "",
-            ContentType::UNKNOWN => "application/octet-stream",
-        }
-        .to_string()
-    }
-
-    pub fn from_ext(ext: &str) -> ContentType {
-        match ext.to_lowercase().as_str() {
-            "html" | "htm" => ContentType::HTML,
-            "json" => ContentType::JSON,
-            "xml" => ContentType::XML,
-            "txt" => ContentType::PLAIN,
-            "css" => ContentType::CSS,
-            "js" => ContentType::JAVASCRIPT,
-            "jpeg" | "jpg" => ContentType::JPEG,
-            "png" => ContentType::PNG,
-            "gif" => ContentType::GIF,
-            "bmp" => ContentType::BMP,
-            "svg" => ContentType::SVG,
-            "webp" => ContentType::WEBP,
-            "mp3" => ContentType::MP3,
-            "mp4" => ContentType::MP4,
-            "wav" => ContentType::WAV,
-            "ogg" => ContentType::OGG,
-            "avi" => ContentType::AVI,
-            "pdf" => ContentType::PDF,
-            "zip" => ContentType::ZIP,
-            "tar" => ContentType::TAR,
-            "gz" => ContentType::GZIP,
-            "bz2" => ContentType::BZIP2,
-            "webm" => ContentType::WEBM,
-            "ico" => ContentType::ICO,
-            "" => ContentType::NONE,
-            _ => { ContentType::UNKNOWN }
-        }
-    }
-
-    pub fn from_file_name(file_name: &str) -> ContentType {
-        if !file_name.contains(".") {
-            return ContentType::UNKNOWN;
-        }
-        let mut splitter = file_name.splitn(2, ".");
-        splitter.next().unwrap();
-        Self::from_ext(splitter.next().unwrap())
-    }
-}
+/// IMF-fixdate, as required for the `Date` header (RFC 9110, section 5.6.7).
+pub const HTTP_DATE_FORMAT_STR: &str = "%a, %d %b %Y %H:%M:%S GMT";
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HttpResponse {
     protocol_v: String,
     response: (HttpCode, String),
@@ -127,38 +27,94 @@ impl HttpResponse {
         reroute: bool,
     ) -> Self {
         let mut header_c = headers.clone();
-        if content.1 != "" {
-            header_c.insert("Content-Length".to_string(), content.0.len().to_string());
-            header_c.insert("Content-Type".to_string(), content.1.clone());
+        let has_content = !content.0.is_empty();
+        header_c
+            .insert("Content-Length", &content.0.len().to_string())
+            .expect("Content-Length is always a valid header");
+        if has_content && !content.1.is_empty() {
+            // Content types from the config or a script may be malformed, in which case they are dropped
+            let _ = header_c.insert("Content-Type", &content.1);
         }
         Self {
             protocol_v: "HTTP/1.1".to_string(),
             response,
             headers: header_c,
-            content: (&content).to_owned(),
-            has_content: content.1 != "",
+            content,
+            has_content,
             reroute,
         }
     }
 
+    /// Adds a header, keeping others of the same name (e.g. `Set-Cookie`).
+    pub fn append_header(&mut self, name: &str, value: &str) -> NetResult<()> {
+        self.headers.append(name, value)
+    }
+
+    /// Adds headers whose names are not set on the response yet.
+    pub fn add_default_headers(&mut self, headers: &Headers) {
+        let existing = self.headers.clone();
+        for (name, value) in headers.iter() {
+            if !existing.contains(name) {
+                let _ = self.headers.append(name, value);
+            }
+        }
+    }
+
+    /// Adds the standard `Date`, `Server` and `Connection` headers,
+    /// unless they were already set (e.g. by a script).
+    pub fn add_standard_headers(&mut self, server: Option<&str>) {
+        let date = Utc::now().format(HTTP_DATE_FORMAT_STR).to_string();
+        let _ = self.headers.insert_default("Date", &date);
+        if let Some(server) = server {
+            let _ = self.headers.insert_default("Server", server);
+        }
+        // Every connection serves a single request
+        let _ = self.headers.insert("Connection", "close");
+    }
+
+    /// Rewrites the body of an uncompressed HTML response, keeping `Content-Length` in step.
+    pub fn map_html(&mut self, f: impl FnOnce(&[u8]) -> Vec<u8>) {
+        let is_html = self.headers.get("Content-Type").is_some_and(|t| {
+            t.split(';')
+                .next()
+                .is_some_and(|t1| t1.trim().eq_ignore_ascii_case("text/html"))
+        });
+        if !is_html || self.headers.contains("Content-Encoding") {
+            return;
+        }
+        self.content.0 = f(&self.content.0);
+        let _ = self
+            .headers
+            .insert("Content-Length", &self.content.0.len().to_string());
+    }
+
     pub fn to_net_error(&self) -> NetError {
-        NetError::new(self.response.0.clone(), Some(self.response.1.clone()))
+        NetError::new(self.response.0, Some(self.response.1.clone()))
     }
 
     pub fn make(&self) -> Vec<u8> {
-        let content_vecu8 = &self.content;
+        let allows_body = self.response.0.allows_body();
+        // The reason phrase may come from a script, so control characters are dropped
+        let reason: String = self.response.1.chars().filter(|c| !c.is_control()).collect();
         let mut r = format!(
             "{} {:?} {}",
             self.protocol_v,
             self.response.0.to_num(),
-            self.response.1
+            reason
         );
         r += "\r";
-        for header in &self.headers {
+        for header in self.headers.iter() {
+            if !allows_body && header.0.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
             r += format!("\n{}: {}", header.0, header.1).as_str()
         }
         r += "\r\n\r\n";
-        [r.into_bytes(), content_vecu8.0.clone()].concat()
+        if allows_body {
+            [r.into_bytes(), self.content.0.clone()].concat()
+        } else {
+            r.into_bytes()
+        }
     }
 
     fn __send(&self, mut stream: &TcpStream) -> Result<(), ()> {
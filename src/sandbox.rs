@@ -0,0 +1,239 @@
+use crate::errors::{DogError, DogResult};
+use crate::logger::Logger;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// What a script wants to do with a path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Directories the file functions of scripts are confined to.
+/// Read-write roots can also be read.
+#[derive(Clone, Debug)]
+pub struct FileSandbox {
+    read_only: Vec<PathBuf>,
+    read_write: Vec<PathBuf>,
+}
+
+impl FileSandbox {
+    pub fn new(logger: &Logger, read_only: &[String], read_write: &[String]) -> DogResult<Self> {
+        let canonical = |dirs: &[String]| {
+            dirs.iter()
+                .map(|dir| {
+                    fs::canonicalize(dir)
+                        .ok()
+                        .filter(|t| t.is_dir())
+                        .ok_or_else(|| {
+                            DogError::new(
+                                logger,
+                                "usr-cfgensure-cfgld".to_string(),
+                                format!("Could not find sandbox directory ({})", dir),
+                            )
+                        })
+                })
+                .collect::<DogResult<Vec<PathBuf>>>()
+        };
+        Ok(Self {
+            read_only: canonical(read_only)?,
+            read_write: canonical(read_write)?,
+        })
+    }
+
+    /// Resolves `path` against the working directory, removes `.` and `..` and follows symlinks,
+    /// then checks that the result lies in a root allowing `access`.
+    /// Parts of the path which do not exist yet are kept as they are, so new files can be written.
+    ///
+    /// The file is opened by path after this check, so whoever can write inside a root can swap a
+    /// directory for a symlink in between and escape it. Only hand writable roots to trusted scripts.
+    pub fn resolve(&self, path: &str, access: Access) -> Result<PathBuf, String> {
+        let denied = || format!("Access to '{}' denied", path);
+        let cwd = std::env::current_dir().map_err(|_| denied())?;
+        let mut normal = PathBuf::new();
+        for component in cwd.join(path).components() {
+            match component {
+                Component::ParentDir => {
+                    normal.pop();
+                }
+                Component::CurDir => {}
+                component => normal.push(component),
+            }
+        }
+
+        let mut existing = normal.as_path();
+        let mut missing = vec![];
+        while fs::symlink_metadata(existing).is_err() {
+            match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    missing.push(name);
+                    existing = parent;
+                }
+                _ => return Err(denied()),
+            }
+        }
+        // Dangling symlinks fail here, so they can not point out of the roots later
+        let mut real = fs::canonicalize(existing).map_err(|_| denied())?;
+        real.extend(missing.iter().rev());
+
+        let allowed = |roots: &[PathBuf]| roots.iter().any(|t| real.starts_with(t));
+        match access {
+            Access::Read if allowed(&self.read_only) || allowed(&self.read_write) => Ok(real),
+            Access::Write if allowed(&self.read_write) => Ok(real),
+            _ => Err(denied()),
+        }
+    }
+
+    /// Whether `path` is a root itself, which scripts may not remove.
+    pub fn is_root(&self, path: &Path) -> bool {
+        self.read_only
+            .iter()
+            .chain(self.read_write.iter())
+            .any(|t| t == path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory with `ro/`, `rw/` and `secret/` in it, removed by the caller.
+    fn base_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("netpup-sandbox-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for sub in ["ro", "rw", "secret"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        fs::write(dir.join("secret/key"), "x").unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    fn sandbox(base: &Path) -> FileSandbox {
+        let logger = Logger::new(false, None).unwrap();
+        let dir = |sub: &str| base.join(sub).to_str().unwrap().to_string();
+        FileSandbox::new(&logger, &[dir("ro")], &[dir("rw")]).unwrap()
+    }
+
+    fn path(base: &Path, rest: &str) -> String {
+        format!("{}/{}", base.to_str().unwrap(), rest)
+    }
+
+    #[test]
+    fn parent_dirs_can_not_escape() {
+        let base = base_dir("parent");
+        let sandbox = sandbox(&base);
+        assert!(sandbox
+            .resolve(&path(&base, "ro/../secret/key"), Access::Read)
+            .is_err());
+        assert!(sandbox
+            .resolve(&path(&base, "rw/../../key"), Access::Write)
+            .is_err());
+        assert!(sandbox
+            .resolve(&path(&base, "rw/a/../../ro/x"), Access::Write)
+            .is_err());
+        assert_eq!(
+            sandbox.resolve(&path(&base, "ro/../rw/./x"), Access::Write),
+            Ok(base.join("rw/x"))
+        );
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn absolute_paths_outside_are_denied() {
+        let base = base_dir("absolute");
+        let sandbox = sandbox(&base);
+        assert!(sandbox.resolve("/etc/passwd", Access::Read).is_err());
+        assert!(sandbox.resolve("/", Access::Read).is_err());
+        assert!(sandbox
+            .resolve(&path(&base, "secret/key"), Access::Read)
+            .is_err());
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn symlinks_out_of_a_root_are_denied() {
+        let base = base_dir("symlink");
+        let sandbox = sandbox(&base);
+        std::os::unix::fs::symlink(base.join("secret"), base.join("rw/link")).unwrap();
+        std::os::unix::fs::symlink(base.join("secret/key"), base.join("ro/key")).unwrap();
+        std::os::unix::fs::symlink(base.join("secret/gone"), base.join("rw/dangling")).unwrap();
+        assert!(sandbox
+            .resolve(&path(&base, "rw/link/key"), Access::Read)
+            .is_err());
+        assert!(sandbox
+            .resolve(&path(&base, "rw/link/new"), Access::Write)
+            .is_err());
+        assert!(sandbox
+            .resolve(&path(&base, "ro/key"), Access::Read)
+            .is_err());
+        assert!(sandbox
+            .resolve(&path(&base, "rw/dangling"), Access::Write)
+            .is_err());
+
+        // Links within the roots are fine
+        std::os::unix::fs::symlink(base.join("ro"), base.join("rw/ro")).unwrap();
+        assert_eq!(
+            sandbox.resolve(&path(&base, "rw/ro/x"), Access::Read),
+            Ok(base.join("ro/x"))
+        );
+        assert!(sandbox
+            .resolve(&path(&base, "rw/ro/x"), Access::Write)
+            .is_err());
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn missing_parents_are_kept() {
+        let base = base_dir("missing");
+        let sandbox = sandbox(&base);
+        assert_eq!(
+            sandbox.resolve(&path(&base, "rw/new/dir/file.txt"), Access::Write),
+            Ok(base.join("rw/new/dir/file.txt"))
+        );
+        assert!(sandbox
+            .resolve(&path(&base, "nope/dir/file.txt"), Access::Write)
+            .is_err());
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn read_only_roots_can_not_be_written() {
+        let base = base_dir("readonly");
+        let sandbox = sandbox(&base);
+        fs::write(base.join("ro/file"), "x").unwrap();
+        assert!(sandbox
+            .resolve(&path(&base, "ro/file"), Access::Read)
+            .is_ok());
+        assert!(sandbox
+            .resolve(&path(&base, "ro/file"), Access::Write)
+            .is_err());
+        assert!(sandbox
+            .resolve(&path(&base, "ro/new"), Access::Write)
+            .is_err());
+        assert!(sandbox
+            .resolve(&path(&base, "rw/file"), Access::Read)
+            .is_ok());
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn roots_themselves() {
+        let base = base_dir("roots");
+        let sandbox = sandbox(&base);
+        let root = sandbox.resolve(&path(&base, "rw/"), Access::Write).unwrap();
+        assert_eq!(root, base.join("rw"));
+        assert!(sandbox.is_root(&root));
+        assert!(sandbox.is_root(&base.join("ro")));
+        assert!(!sandbox.is_root(&base.join("rw/x")));
+        assert!(!sandbox.is_root(&base));
+
+        let logger = Logger::new(false, None).unwrap();
+        let missing = path(&base, "missing");
+        assert!(FileSandbox::new(&logger, &[missing], &[]).is_err());
+        let file = path(&base, "secret/key");
+        assert!(FileSandbox::new(&logger, &[], &[file]).is_err());
+        let _ = fs::remove_dir_all(base);
+    }
+}
@@ -1,59 +1,596 @@
-use crate::errors::{DogError, DogResult, HttpCode};
+use crate::cookies;
+use crate::errors::{DogError, DogResult, HttpCode, NetError};
+use crate::forms::{self, FileData, FormConfig};
+use crate::headers::Headers;
 use crate::logger::Logger;
+use crate::lualib::{builtin_module, json_decode, json_to_lua, lua_to_json};
 use crate::request::HttpRequest;
-use crate::response::{ContentType, HttpResponse};
+use crate::mime::MimeRegistry;
+use crate::response::HttpResponse;
+use crate::sandbox::{Access, FileSandbox};
+use crate::sessions::{Session, Sessions};
 use mlua;
 use mlua::prelude::LuaError;
-use mlua::{Function, Lua, StdLib, Table, UserData};
-use std::collections::HashMap;
+use mlua::{
+    AnyUserData, Function, HookTriggers, Lua, LuaSerdeExt, StdLib, Table, Thread, UserData,
+    UserDataFields, UserDataMethods, Value, VmState,
+};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::sync::{Arc, Mutex};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-impl UserData for HttpRequest {}
+impl UserData for HttpRequest {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("method", |_, this| Ok(format!("{:?}", this.method)));
+        fields.add_field_method_get("path", |_, this| Ok(this.path.clone()));
+        fields.add_field_method_get("host", |_, this| Ok(this.host.clone()));
+        fields.add_field_method_get("user", |_, this| Ok(this.user.clone()));
+        fields.add_field_method_get("client_ip", |_, this| {
+            Ok(this.client_ip.map(|t| t.to_string()))
+        });
+        fields.add_field_method_get("csp_nonce", |_, this| Ok(this.csp_nonce.clone()));
+        fields.add_field_method_get("body", |lua, this| lua.create_string(&this.body));
+        fields.add_field_method_get("cookies", |lua, this| {
+            let cookies = lua.create_table()?;
+            for header in this.headers.get_all("Cookie") {
+                for (name, value) in cookies::parse(header) {
+                    if !cookies.contains_key(name.as_str())? {
+                        cookies.set(name, value)?;
+                    }
+                }
+            }
+            Ok(cookies)
+        });
+        fields.add_field_method_get("claims", |lua, this| match &this.claims {
+            Some(claims) => lua.to_value(claims),
+            None => Ok(Value::Nil),
+        });
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("header", |_, this, name: String| {
+            Ok(this.headers.get(&name).map(|t| t.to_string()))
+        });
+        // Errors raised here answer the request with 415 / 400 unless the script catches them
+        methods.add_method("json", |lua, this, ()| {
+            let media_type = this
+                .headers
+                .get("Content-Type")
+                .and_then(|t| t.split(';').next())
+                .map(|t| t.trim().to_ascii_lowercase())
+                .unwrap_or_default();
+            if media_type != "application/json" && !media_type.ends_with("+json") {
+                return Err(LuaError::external(NetError::new(
+                    HttpCode::UNSUPPORTED_MEDIA_TYPE,
+                    Some("Expected an application/json body".to_string()),
+                )));
+            }
+            json_decode(lua, &this.body).map_err(|e| {
+                LuaError::external(NetError::new(
+                    HttpCode::BAD_REQUEST,
+                    Some(format!("Malformed JSON body ({})", e)),
+                ))
+            })
+        });
+        // Parsed once, later calls return the same tables
+        methods.add_function("form", |lua, ud: AnyUserData| {
+            if let Some(form) = ud.user_value::<Option<Table>>()? {
+                return Ok((form.get::<Table>("fields")?, form.get::<Table>("files")?));
+            }
+            let form = {
+                let this = ud.borrow::<HttpRequest>()?;
+                let config = lua
+                    .app_data_ref::<FormConfig>()
+                    .map(|t| t.clone())
+                    .unwrap_or_default();
+                forms::parse(&this, &config).map_err(LuaError::external)?
+            };
+            if let Some(mut uploads) = lua.app_data_mut::<TempUploads>() {
+                uploads.0.extend(form.temp_files());
+            }
+
+            let fields = lua.create_table()?;
+            for (name, value) in form.fields {
+                push_form_value(
+                    lua,
+                    &fields,
+                    &name,
+                    Value::String(lua.create_string(value)?),
+                )?;
+            }
+            let files = lua.create_table()?;
+            for file in form.files {
+                let t = lua.create_table()?;
+                t.set("name", file.name.as_str())?;
+                t.set("filename", file.filename)?;
+                t.set("content_type", file.content_type)?;
+                t.set("size", file.size)?;
+                match file.data {
+                    FileData::Memory(data) => t.set("data", lua.create_string(data)?)?,
+                    FileData::Temp(path) => t.set("path", path.display().to_string())?,
+                }
+                push_form_value(lua, &files, &file.name, Value::Table(t))?;
+            }
+            let form = lua.create_table()?;
+            form.set("fields", &fields)?;
+            form.set("files", &files)?;
+            ud.set_user_value(form)?;
+            Ok((fields, files))
+        });
+    }
+}
 impl UserData for HttpResponse {}
 
+/// Sets `table[name]`, turning the entry into a list if the name is used several times.
+fn push_form_value(lua: &Lua, table: &Table, name: &str, value: Value) -> Result<(), LuaError> {
+    let marker = form_list(lua)?;
+    match table.get::<Value>(name)? {
+        Value::Nil => table.set(name, value),
+        Value::Table(list) if list.metatable().is_some_and(|t| t == marker) => list.push(value),
+        first => {
+            let list = lua.create_sequence_from([first, value])?;
+            list.set_metatable(Some(marker));
+            table.set(name, list)
+        }
+    }
+}
+
+/// Marks the lists `push_form_value` made, file tables are tables too.
+fn form_list(lua: &Lua) -> Result<Table, LuaError> {
+    match lua.named_registry_value::<Option<Table>>(FORM_LIST)? {
+        Some(marker) => Ok(marker),
+        None => {
+            let marker = lua.create_table()?;
+            lua.set_named_registry_value(FORM_LIST, &marker)?;
+            Ok(marker)
+        }
+    }
+}
+
+/// Runs `f` on the session of the current request.
+fn with_session<T>(lua: &Lua, f: impl FnOnce(&mut Session) -> T) -> Result<T, LuaError> {
+    match lua.app_data_mut::<Session>() {
+        Some(mut session) => Ok(f(&mut session)),
+        None => Err(LuaError::runtime("Sessions are not configured")),
+    }
+}
+
+/// The `session` global, working on the session of whichever request the state runs.
+fn init_session(lua: &Lua) -> Result<(), LuaError> {
+    let session = lua.create_table()?;
+    session.set(
+        "get",
+        lua.create_function(|lua, key: String| {
+            let value = with_session(lua, |t| t.get(&key).cloned())?;
+            match value {
+                Some(value) => json_to_lua(lua, value),
+                None => Ok(Value::Nil),
+            }
+        })?,
+    )?;
+    session.set(
+        "set",
+        lua.create_function(|lua, (key, value): (String, Value)| {
+            let value = lua_to_json(lua, &value, 0)?;
+            with_session(lua, |t| t.set(&key, value))
+        })?,
+    )?;
+    session.set(
+        "destroy",
+        lua.create_function(|lua, ()| with_session(lua, |t| t.destroy()))?,
+    )?;
+    session.set(
+        "rotate",
+        lua.create_function(|lua, ()| with_session(lua, |t| t.rotate()))?,
+    )?;
+    lua.globals().set("session", session)
+}
+
+/// Uploads written to disk while a script runs, removed when it is done.
+#[derive(Default)]
+struct TempUploads(Vec<PathBuf>);
+
+/// The source of a route script as last read from disk.
+#[derive(Debug)]
+struct ScriptSource {
+    path: String,
+    src: Vec<u8>,
+}
+
+impl ScriptSource {
+    /// Compiles the source, syntax errors name the script path and line.
+    fn compile(&self, lua: &Lua) -> Result<Function, LuaError> {
+        lua.load(&self.src)
+            .set_name(format!("@{}", self.path))
+            .into_function()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Script {
     path: String,
     function: Function,
+    /// What the function was compiled from, to notice reloaded scripts
+    source: Arc<ScriptSource>,
 }
 
 impl Script {
-    pub fn new(lua: &Lua, path: String) -> Result<Self, ()> {
-        let src = fs::read(&path);
-        if src.is_err() {
-            return Err(());
-        }
-        let function = lua
-            .load(src.unwrap())
-            .set_name(&path)
-            .into_function()
-            .unwrap();
-        Ok(Self { path, function })
+    fn new(lua: &Lua, source: Arc<ScriptSource>) -> Result<Self, LuaError> {
+        let function = source.compile(lua)?;
+        Ok(Self {
+            path: source.path.clone(),
+            function,
+            source,
+        })
+    }
+
+    pub fn run(&self, request: HttpRequest) -> Result<Table, LuaError> {
+        self.function.call::<Table>(request)
+    }
+}
+
+/// Per-route limits for running a script, `timeout_ms` and `memory_limit` in the config.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptLimits {
+    pub timeout: Option<Duration>,
+    /// Memory of the whole Lua state running the script, in bytes
+    pub memory: Option<usize>,
+}
+
+/// The client error a script raised through the request object, e.g. for a malformed body.
+fn client_error(e: &LuaError) -> Option<&NetError> {
+    match e {
+        LuaError::ExternalError(e) => e.downcast_ref::<NetError>(),
+        LuaError::CallbackError { cause, .. } => client_error(cause),
+        LuaError::WithContext { cause, .. } => client_error(cause),
+        _ => None,
+    }
+}
+
+fn is_memory_error(e: &LuaError) -> bool {
+    match e {
+        LuaError::MemoryError(_) => true,
+        LuaError::CallbackError { cause, .. } => is_memory_error(cause),
+        _ => false,
+    }
+}
+
+/// How often the time limit of a script is checked, in Lua VM instructions.
+const HOOK_INSTRUCTIONS: u32 = 10_000;
+
+/// Error raised in scripts which ran out of time. Only the time limit hook creates it,
+/// so scripts can neither forge it nor mistake another error for it.
+#[derive(Debug)]
+struct ScriptTimeout;
+
+impl std::fmt::Display for ScriptTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "netpup: script timed out")
+    }
+}
+
+impl std::error::Error for ScriptTimeout {}
+
+fn is_timeout(e: &LuaError) -> bool {
+    match e {
+        LuaError::CallbackError { cause, .. } | LuaError::WithContext { cause, .. } => {
+            is_timeout(cause)
+        }
+        e => e.downcast_ref::<ScriptTimeout>().is_some(),
     }
+}
+
+/// Deadline of the running script, kept as app data so coroutines can be hooked as well.
+#[derive(Clone, Debug)]
+struct TimeLimit {
+    deadline: Instant,
+    timed_out: Arc<AtomicBool>,
+}
 
-    pub fn run(&self, request: HttpRequest) -> Result<Table, Box<dyn std::error::Error>> {
-        Ok(self.function.call::<Table>(request)?)
+impl TimeLimit {
+    /// Stops `thread` with a `ScriptTimeout` once the deadline passed.
+    /// Only one thread is hooked at a time, so this is repeated whenever another one runs.
+    fn hook(&self, thread: &Thread) {
+        let limit = self.clone();
+        thread.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+            move |_lua, _debug| {
+                if Instant::now() < limit.deadline {
+                    return Ok(VmState::Continue);
+                }
+                limit.timed_out.store(true, Ordering::SeqCst);
+                Err(LuaError::external(ScriptTimeout))
+            },
+        );
     }
 }
 
+/// Makes `pcall`, `xpcall` and the coroutine functions pass the timeout error on,
+/// so scripts can not catch it and keep running, and moves the time limit to resumed coroutines.
+/// Gets the Rust `is_timeout` check and the function hooking a thread as arguments.
+const GUARD_PCALL: &str = r#"
+local is_timeout, hook_thread = ...
+local pcall, xpcall, error = pcall, xpcall, error
+local create, resume, running, close = coroutine.create, coroutine.resume, coroutine.running, coroutine.close
+local function pass_timeout(ok, ...)
+    if not ok and is_timeout((...)) then
+        error((...), 0)
+    end
+    return ok, ...
+end
+_G.pcall = function(f, ...)
+    return pass_timeout(pcall(f, ...))
+end
+_G.xpcall = function(f, handler, ...)
+    return pass_timeout(xpcall(f, function(e)
+        if is_timeout(e) then
+            return e
+        end
+        return handler(e)
+    end, ...))
+end
+local function resumed(ok, ...)
+    hook_thread((running()))
+    return pass_timeout(ok, ...)
+end
+local function guarded_resume(co, ...)
+    hook_thread(co)
+    return resumed(resume(co, ...))
+end
+local function unwrap(ok, ...)
+    if not ok then
+        error((...), 0)
+    end
+    return ...
+end
+coroutine.resume = guarded_resume
+coroutine.close = function(co)
+    return pass_timeout(close(co))
+end
+coroutine.wrap = function(f)
+    local co = create(f)
+    return function(...)
+        return unwrap(guarded_resume(co, ...))
+    end
+end
+"#;
+
+/// Replaces the functions catching errors, see `GUARD_PCALL`.
+fn guard_timeouts(lua: &Lua) -> mlua::Result<()> {
+    let is_timeout_fn = lua.create_function(|_, value: Value| {
+        Ok(matches!(value, Value::Error(e) if is_timeout(&e)))
+    })?;
+    let hook_thread_fn = lua.create_function(|lua, thread: Thread| {
+        let limit = lua.app_data_ref::<TimeLimit>().map(|t| t.clone());
+        if let Some(limit) = limit {
+            limit.hook(&thread);
+        }
+        Ok(())
+    })?;
+    lua.load(GUARD_PCALL).call((is_timeout_fn, hook_thread_fn))
+}
+
+/// Registry key of the table holding the hooks registered with `on_shutdown`.
+const SHUTDOWN_HOOKS: &str = "netpup_shutdown_hooks";
+
+/// Registry key of the table caching modules loaded with `require`, also `package.loaded`.
+const LOADED_MODULES: &str = "netpup_loaded_modules";
+
+/// Registry key of the metatable marking lists of form values with the same name.
+const FORM_LIST: &str = "netpup_form_list";
+
+/// Pause between a change event and reading the script, so writes in several steps can finish.
+const RELOAD_DELAY: Duration = Duration::from_millis(50);
+
+/// An isolated Lua state with the netpup globals and all scripts compiled.
+#[derive(Debug)]
+struct LuaState {
+    lua: Lua,
+    scripts: HashMap<String, Script>,
+}
+
+/// Runs route scripts on a pool of identical Lua states.
+/// Each request checks out a state of its own, so scripts run in parallel.
 #[derive(Clone, Debug)]
 pub struct ScriptLoader {
-    _lua: Arc<Mutex<Lua>>,
-    scripts: HashMap<String, Script>,
+    /// Route name -> current source, replaced as a whole when a script is reloaded
+    sources: Arc<RwLock<HashMap<String, Arc<ScriptSource>>>>,
+    states: Arc<Mutex<Vec<LuaState>>>,
+    /// Canonical directories `require` loads modules from
+    lua_path: Arc<Vec<PathBuf>>,
+    sandbox: Arc<FileSandbox>,
+    forms: FormConfig,
+    sessions: Option<Arc<Sessions>>,
     logger: Logger,
+    mime: Arc<MimeRegistry>,
 }
 
-unsafe impl Send for ScriptLoader {}
 
-fn _lua_read(lua: &Lua, path: String) -> Result<mlua::String, LuaError> {
+/// Resolves a path to read, which may also be one of the request's own uploads in `upload_dir`.
+fn resolve_read(lua: &Lua, sandbox: &FileSandbox, path: &str) -> Result<PathBuf, LuaError> {
+    sandbox.resolve(path, Access::Read).or_else(|e| {
+        let upload = fs::canonicalize(path).ok().filter(|t| {
+            lua.app_data_ref::<TempUploads>()
+                .is_some_and(|uploads| uploads.0.contains(t))
+        });
+        upload.ok_or_else(|| LuaError::runtime(e))
+    })
+}
+
+fn _lua_read(lua: &Lua, sandbox: &FileSandbox, path: String) -> Result<mlua::String, LuaError> {
+    let path = resolve_read(lua, sandbox, &path)?;
+    // Creating the string fails with a Lua error once the memory limit is reached
     fs::read_to_string(path)
-        .map(|t| lua.convert(t).unwrap())
         .or(Err(LuaError::runtime("Unable to read file")))
+        .and_then(|t| lua.create_string(t))
+}
+
+fn _lua_read_bytes(
+    lua: &Lua,
+    sandbox: &FileSandbox,
+    path: String,
+) -> Result<mlua::String, LuaError> {
+    let path = resolve_read(lua, sandbox, &path)?;
+    fs::read(path)
+        .or(Err(LuaError::runtime("Unable to read file")))
+        .and_then(|t| lua.create_string(t))
 }
 
-fn _lua_write(_lua: &Lua, (path, content): (String, String)) -> Result<(), LuaError> {
-    fs::write(path, content).or(Err(LuaError::runtime("Unable to read file")))
+fn _lua_write(
+    sandbox: &FileSandbox,
+    (path, content): (String, mlua::String),
+) -> Result<(), LuaError> {
+    let path = sandbox
+        .resolve(&path, Access::Write)
+        .map_err(LuaError::runtime)?;
+    fs::write(path, content.as_bytes()).or(Err(LuaError::runtime("Unable to write file")))
+}
+
+fn _lua_append(
+    sandbox: &FileSandbox,
+    (path, content): (String, mlua::String),
+) -> Result<(), LuaError> {
+    let path = sandbox
+        .resolve(&path, Access::Write)
+        .map_err(LuaError::runtime)?;
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut t| t.write_all(&content.as_bytes()))
+        .or(Err(LuaError::runtime("Unable to write file")))
+}
+
+fn _lua_exists(lua: &Lua, sandbox: &FileSandbox, path: String) -> Result<bool, LuaError> {
+    let path = resolve_read(lua, sandbox, &path)?;
+    Ok(path.exists())
+}
+
+/// Names of the entries of a directory, sorted.
+fn _lua_list_dir(sandbox: &FileSandbox, path: String) -> Result<Vec<String>, LuaError> {
+    let path = sandbox
+        .resolve(&path, Access::Read)
+        .map_err(LuaError::runtime)?;
+    let mut names = fs::read_dir(path)
+        .and_then(|t| {
+            t.map(|entry| entry.map(|t| t.file_name().to_string_lossy().to_string()))
+                .collect::<Result<Vec<String>, _>>()
+        })
+        .or(Err(LuaError::runtime("Unable to list directory")))?;
+    names.sort();
+    Ok(names)
+}
+
+/// Size, type and modification time of a file or directory, `nil` if it does not exist.
+fn _lua_stat(lua: &Lua, sandbox: &FileSandbox, path: String) -> Result<Option<Table>, LuaError> {
+    let path = resolve_read(lua, sandbox, &path)?;
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(None),
+    };
+    let stat = lua.create_table()?;
+    stat.set("size", metadata.len())?;
+    stat.set("is_file", metadata.is_file())?;
+    stat.set("is_dir", metadata.is_dir())?;
+    stat.set("readonly", metadata.permissions().readonly())?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|t| t.as_secs());
+    stat.set("modified", modified)?;
+    Ok(Some(stat))
+}
+
+/// Removes a file or an empty directory.
+fn _lua_remove(sandbox: &FileSandbox, path: String) -> Result<(), LuaError> {
+    let path = sandbox
+        .resolve(&path, Access::Write)
+        .map_err(LuaError::runtime)?;
+    if sandbox.is_root(&path) {
+        return Err(LuaError::runtime("Unable to remove a sandbox directory"));
+    }
+    match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir(path),
+        _ => fs::remove_file(path),
+    }
+    .or(Err(LuaError::runtime("Unable to remove file")))
+}
+
+/// Module names are dot separated like `lib.auth`, so they can not leave the `lua_path` directories.
+fn is_module_name(name: &str) -> bool {
+    name.split('.').all(|t| {
+        !t.is_empty()
+            && t.chars()
+                .all(|t| t.is_ascii_alphanumeric() || t == '_' || t == '-')
+    })
+}
+
+/// Finds `name` as `<dir>/<name>.lua` or `<dir>/<name>/init.lua` in the first directory having it.
+/// Symlinks pointing out of the directory are ignored.
+fn find_module(lua_path: &[PathBuf], name: &str) -> Option<PathBuf> {
+    let rel: PathBuf = name.split('.').collect();
+    for dir in lua_path {
+        let candidates = [
+            dir.join(&rel).with_extension("lua"),
+            dir.join(&rel).join("init.lua"),
+        ];
+        for candidate in candidates {
+            if let Ok(file) = fs::canonicalize(&candidate) {
+                if file.starts_with(dir) && file.is_file() {
+                    return Some(file);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// `require` restricted to the built-in modules and `lua_path`. Modules are loaded once per Lua state.
+fn _lua_require(lua: &Lua, lua_path: &[PathBuf], name: String) -> Result<Value, LuaError> {
+    let loaded: Table = lua.named_registry_value(LOADED_MODULES)?;
+    let cached: Value = loaded.get(name.as_str())?;
+    if !cached.is_nil() {
+        return Ok(cached);
+    }
+    let module = match builtin_module(lua, &name) {
+        Some(module) => Value::Table(module?),
+        None => {
+            if !is_module_name(&name) {
+                return Err(LuaError::runtime(format!("Invalid module name '{}'", name)));
+            }
+            let file = find_module(lua_path, &name).ok_or_else(|| {
+                LuaError::runtime(format!("Module '{}' not found in lua_path", name))
+            })?;
+            let src = fs::read(&file).or(Err(LuaError::runtime(format!(
+                "Unable to read module '{}'",
+                name
+            ))))?;
+            let file = file.display().to_string();
+            let value: Value = lua
+                .load(src)
+                .set_name(format!("@{}", file))
+                .call((name.as_str(), file.as_str()))?;
+            match value {
+                Value::Nil => Value::Boolean(true),
+                value => value,
+            }
+        }
+    };
+    loaded.set(name.as_str(), &module)?;
+    Ok(module)
+}
+
+/// Scripts run on every request, so hooks are kept per script and the latest one wins.
+fn _lua_on_shutdown(lua: &Lua, hook: Function) -> Result<(), LuaError> {
+    let hooks: Table = lua.named_registry_value(SHUTDOWN_HOOKS)?;
+    hooks.set(hook.info().source.unwrap_or_default(), hook)
 }
 
 fn _mk_logger(lua: &Lua) -> Result<Logger, LuaError> {
@@ -85,33 +622,82 @@ fn _lua_log_fatal(lua: &Lua, msg: String) -> Result<(), LuaError> {
 }
 
 impl ScriptLoader {
-    pub fn new(logger: &Logger, script_locs: HashMap<String, String>) -> DogResult<Self> {
+    pub fn new(
+        logger: &Logger,
+        script_locs: HashMap<String, String>,
+        lua_path: Vec<String>,
+        sandbox: FileSandbox,
+        forms: FormConfig,
+        sessions: Option<Sessions>,
+        mime: Arc<MimeRegistry>,
+    ) -> DogResult<Self> {
+        let lua_path = lua_path
+            .iter()
+            .map(|dir| {
+                fs::canonicalize(dir)
+                    .ok()
+                    .filter(|t| t.is_dir())
+                    .ok_or_else(|| {
+                        DogError::new(
+                            logger,
+                            "usr-scripts-luapath".to_string(),
+                            format!("Could not find lua_path directory ({})", dir),
+                        )
+                    })
+            })
+            .collect::<DogResult<Vec<PathBuf>>>()?;
+        let mut sources = HashMap::new();
+        for (name, path) in script_locs {
+            let src = fs::read(&path);
+            if src.is_err() {
+                return Err(DogError::new(
+                    logger,
+                    "usr-scripts-ensloc".to_string(),
+                    "Could not ensure that all scripts exist".to_string(),
+                ));
+            }
+            let source = ScriptSource {
+                path,
+                src: src.unwrap(),
+            };
+            sources.insert(name, Arc::new(source));
+        }
+
+        let loader = Self {
+            sources: Arc::new(RwLock::new(sources)),
+            states: Arc::new(Mutex::new(vec![])),
+            lua_path: Arc::new(lua_path),
+            sandbox: Arc::new(sandbox),
+            forms,
+            sessions: sessions.map(Arc::new),
+            logger: logger.clone(),
+            mime,
+        };
+        // The first state makes sure all scripts compile
+        let state = loader.new_state()?;
+        loader.states.lock().unwrap().push(state);
+        Ok(loader)
+    }
+
+    fn new_state(&self) -> DogResult<LuaState> {
         let lua = Lua::new();
         lua.load_std_libs(StdLib::ALL_SAFE)
             .expect("Panic on Lua load stdlib");
         let globals = lua.globals();
         // Logging stuff
-        if (&logger).write_file.is_some() {
+        if self.logger.write_file.is_some() {
             globals
                 .set(
                     "__logger_file",
-                    logger.write_file.clone().unwrap().to_owned(),
+                    self.logger.write_file.clone().unwrap().to_owned(),
                 )
                 .expect("Panic on Lua globals init");
         }
         globals
-            .set("__logger_print", logger.do_print)
+            .set("__logger_print", self.logger.do_print)
             .expect("Panic on Lua globals init");
         // Included functions
-        globals
-            .set("read".to_string(), lua.create_function(_lua_read).unwrap())
-            .expect("Panic on Lua globals init");
-        globals
-            .set(
-                "write".to_string(),
-                lua.create_function(_lua_write).unwrap(),
-            )
-            .expect("Panic on Lua globals init");
+        self.init_files(&lua).expect("Panic on Lua globals init");
         globals
             .set(
                 "log_info".to_string(),
@@ -130,25 +716,274 @@ impl ScriptLoader {
                 lua.create_function(_lua_log_fatal).unwrap(),
             )
             .expect("Panic on Lua globals init");
-        let mut scripts = HashMap::new();
+        globals
+            .set(
+                "on_shutdown".to_string(),
+                lua.create_function(_lua_on_shutdown).unwrap(),
+            )
+            .expect("Panic on Lua globals init");
+        lua.set_named_registry_value(SHUTDOWN_HOOKS, lua.create_table().unwrap())
+            .expect("Panic on Lua globals init");
+        self.init_require(&lua).expect("Panic on Lua globals init");
+        lua.set_app_data(self.forms.clone());
+        lua.set_app_data(TempUploads::default());
+        init_session(&lua).expect("Panic on Lua globals init");
+        guard_timeouts(&lua).expect("Panic on Lua globals init");
+        let mut state = LuaState {
+            lua,
+            scripts: HashMap::new(),
+        };
+        self.refresh(&mut state)?;
+        Ok(state)
+    }
 
-        for script_loc in script_locs {
-            let script = Script::new(&lua, script_loc.1);
-            if script.is_err() {
-                return Err(DogError::new(
-                    &logger,
-                    "usr-scripts-ensloc".to_string(),
-                    "Could not ensure that all scripts exist".to_string(),
-                ));
+    /// Sets up the file functions confined to the sandbox and removes the standard ones which are not.
+    fn init_files(&self, lua: &Lua) -> Result<(), LuaError> {
+        let globals = lua.globals();
+        for name in ["io", "dofile", "loadfile"] {
+            globals.set(name, Value::Nil)?;
+        }
+        let os: Table = globals.get("os")?;
+        for name in ["execute", "exit", "remove", "rename", "tmpname"] {
+            os.set(name, Value::Nil)?;
+        }
+        let std_package: Table = globals.get("package")?;
+        let std_loaded: Table = std_package.get("loaded")?;
+        std_loaded.set("io", Value::Nil)?;
+
+        let sandbox = &self.sandbox;
+        let s = sandbox.clone();
+        globals.set(
+            "read",
+            lua.create_function(move |lua, path| _lua_read(lua, &s, path))?,
+        )?;
+        let s = sandbox.clone();
+        globals.set(
+            "read_bytes",
+            lua.create_function(move |lua, path| _lua_read_bytes(lua, &s, path))?,
+        )?;
+        let s = sandbox.clone();
+        globals.set(
+            "write",
+            lua.create_function(move |_, args| _lua_write(&s, args))?,
+        )?;
+        let s = sandbox.clone();
+        globals.set(
+            "append",
+            lua.create_function(move |_, args| _lua_append(&s, args))?,
+        )?;
+        let s = sandbox.clone();
+        globals.set(
+            "exists",
+            lua.create_function(move |lua, path| _lua_exists(lua, &s, path))?,
+        )?;
+        let s = sandbox.clone();
+        globals.set(
+            "list_dir",
+            lua.create_function(move |_, path| _lua_list_dir(&s, path))?,
+        )?;
+        let s = sandbox.clone();
+        globals.set(
+            "stat",
+            lua.create_function(move |lua, path| _lua_stat(lua, &s, path))?,
+        )?;
+        let s = sandbox.clone();
+        globals.set(
+            "remove",
+            lua.create_function(move |_, path| _lua_remove(&s, path))?,
+        )
+    }
+
+    /// Replaces `require` and `package`, so modules only come from the built-ins and `lua_path`.
+    fn init_require(&self, lua: &Lua) -> Result<(), LuaError> {
+        let globals = lua.globals();
+        let loaded = lua.create_table()?;
+        // Keep the standard libraries loadable, but not the searchers of the old `package`
+        let std_package: Table = globals.get("package")?;
+        let std_loaded: Table = std_package.get("loaded")?;
+        for pair in std_loaded.pairs::<String, Value>() {
+            let (name, value) = pair?;
+            if name != "package" {
+                loaded.set(name, value)?;
             }
-            scripts.insert(script_loc.0, script.unwrap());
         }
+        let package = lua.create_table()?;
+        package.set("loaded", &loaded)?;
+        loaded.set("package", &package)?;
+        globals.set("package", package)?;
+        lua.set_named_registry_value(LOADED_MODULES, loaded)?;
 
-        Ok(Self {
-            _lua: Arc::new(Mutex::new(lua)),
-            logger: logger.clone(),
-            scripts,
-        })
+        let lua_path = self.lua_path.clone();
+        globals.set(
+            "require",
+            lua.create_function(move |lua, name: String| _lua_require(lua, &lua_path, name))?,
+        )
+    }
+
+    /// Compiles the scripts which changed since the state last ran them.
+    /// The rest of the state, like globals and shutdown hooks, is kept.
+    fn refresh(&self, state: &mut LuaState) -> DogResult<()> {
+        let sources = self.sources.read().unwrap().clone();
+        for (name, source) in sources {
+            if state
+                .scripts
+                .get(&name)
+                .is_some_and(|t| Arc::ptr_eq(&t.source, &source))
+            {
+                continue;
+            }
+            let path = source.path.clone();
+            let script = Script::new(&state.lua, source).map_err(|e| {
+                DogError::new(
+                    &self.logger,
+                    "usr-scripts-compile".to_string(),
+                    format!("Could not compile script ({}) => {}", path, e),
+                )
+            })?;
+            state.scripts.insert(name, script);
+        }
+        Ok(())
+    }
+
+    /// Reads the script at `path` again and swaps it in for all routes using it.
+    /// If it does not compile, the previous version keeps serving.
+    fn reload(&self, path: &str) {
+        let src = match fs::read(path) {
+            Ok(src) => src,
+            Err(e) => {
+                DogError::new(
+                    &self.logger,
+                    "usr-scripts-reload".to_string(),
+                    format!(
+                        "Could not read script ({}), keeping the previous version => {}",
+                        path, e
+                    ),
+                );
+                return;
+            }
+        };
+        let source = Arc::new(ScriptSource {
+            path: path.to_string(),
+            src,
+        });
+        let unchanged = self
+            .sources
+            .read()
+            .unwrap()
+            .values()
+            .any(|t| t.path == path && t.src == source.src);
+        if unchanged {
+            return;
+        }
+        if let Err(e) = source.compile(&Lua::new()) {
+            DogError::new(
+                &self.logger,
+                "usr-scripts-compile".to_string(),
+                format!(
+                    "Could not compile script ({}), keeping the previous version => {}",
+                    path, e
+                ),
+            );
+            return;
+        }
+
+        let mut sources = self.sources.write().unwrap();
+        for current in sources.values_mut().filter(|t| t.path == path) {
+            *current = source.clone();
+        }
+        drop(sources);
+        self.logger
+            .clone()
+            .info(format!("Reloaded script ({})", path).as_str());
+    }
+
+    /// Watches the directories of all scripts and reloads scripts when their files change.
+    /// Directories are watched rather than files, as editors often replace a file on save.
+    /// Reloading stops when the returned watcher is dropped.
+    pub fn watch(&self) -> DogResult<RecommendedWatcher> {
+        let watch_error = |e: notify::Error| {
+            DogError::new(
+                &self.logger,
+                "usr-scripts-watch".to_string(),
+                format!("Could not watch scripts for changes => {}", e),
+            )
+        };
+        // Canonical file path -> script path as configured
+        let mut targets = HashMap::new();
+        for source in self.sources.read().unwrap().values() {
+            let file = fs::canonicalize(&source.path).map_err(|e| watch_error(e.into()))?;
+            targets.insert(file, source.path.clone());
+        }
+        let dirs: HashSet<PathBuf> = targets
+            .keys()
+            .filter_map(|t| t.parent().map(|t| t.to_path_buf()))
+            .collect();
+
+        let loader = self.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        loader
+                            .logger
+                            .clone()
+                            .error(format!("Watching scripts failed => {}", e).as_str());
+                        return;
+                    }
+                };
+                if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    return;
+                }
+                let paths: HashSet<&String> =
+                    event.paths.iter().filter_map(|t| targets.get(t)).collect();
+                if paths.is_empty() {
+                    return;
+                }
+                thread::sleep(RELOAD_DELAY);
+                for path in paths {
+                    loader.reload(path);
+                }
+            })
+            .map_err(watch_error)?;
+        for dir in dirs {
+            watcher
+                .watch(&dir, RecursiveMode::NonRecursive)
+                .map_err(watch_error)?;
+        }
+        Ok(watcher)
+    }
+
+    /// Converts the `headers` entry of a response table.
+    /// Values may be a string or a list of strings (e.g. for several `Set-Cookie` headers).
+    fn table_to_headers(&self, table: Table) -> DogResult<Headers> {
+        let mut headers = Headers::new();
+        for pair in table.pairs::<String, Value>() {
+            let (name, value) = pair.map_err(|e| {
+                DogError::new(
+                    &self.logger,
+                    "usr-scripts-evres".to_string(),
+                    format!("Malformed entry 'headers' in response table => {}", e),
+                )
+            })?;
+            let values = match value {
+                Value::Table(values) => values.sequence_values::<String>().collect(),
+                value => vec![value.to_string()],
+            };
+            for value in values {
+                let value = value.map_err(|e| {
+                    DogError::new(
+                        &self.logger,
+                        "usr-scripts-evres".to_string(),
+                        format!("Malformed value for header '{}' => {}", name, e),
+                    )
+                })?;
+                headers.append(&name, &value).map_err(|e| {
+                    DogError::new(&self.logger, "usr-scripts-evres".to_string(), e.details)
+                })?;
+            }
+        }
+        Ok(headers)
     }
 
     pub fn table_to_response(&self, table: Table) -> DogResult<HttpResponse> {
@@ -159,13 +994,6 @@ impl ScriptLoader {
                 "Missing 'code' in response table".to_string(),
             ));
         }
-        if !table.contains_key("resp").unwrap() {
-            return Err(DogError::new(
-                &self.logger,
-                "usr-scripts-evres".to_string(),
-                "Missing 'resp' in response table".to_string(),
-            ));
-        }
         if !table.contains_key("headers").unwrap() {
             return Err(DogError::new(
                 &self.logger,
@@ -183,7 +1011,7 @@ impl ScriptLoader {
 
         let reroute = table.contains_key("reroute").unwrap()
             && (table.get::<bool>("reroute").unwrap() == true);
-        let code = HttpCode::from_num(table.get("code").unwrap());
+        let code = table.get::<u16>("code").ok().and_then(HttpCode::from_num);
         if code.is_none() {
             return Err(DogError::new(
                 &self.logger,
@@ -191,31 +1019,252 @@ impl ScriptLoader {
                 "Malformed entry 'code' in response table".to_string(),
             ));
         }
+        let code = code.unwrap();
+        let resp = table
+            .get::<Option<String>>("resp")
+            .unwrap()
+            .unwrap_or_else(|| code.canonical_reason().to_string());
         let content: String = table.get("content").unwrap();
-        let ct: String = table.get("type").unwrap();
+        let ct: String = table
+            .get::<Option<String>>("type")
+            .unwrap()
+            .unwrap_or_default();
 
         Ok(HttpResponse::new(
-            (code.unwrap(), table.get("resp").unwrap()),
-            table.get("headers").unwrap(),
-            (content.into_bytes(), ContentType::from_ext(ct.as_str()).to_string()),
+            (code, resp),
+            self.table_to_headers(table.get("headers").unwrap())?,
+            (content.into_bytes(), self.mime.resolve(&ct)),
             reroute,
         ))
     }
 
-    pub fn run_script(&self, script: &str, request: HttpRequest) -> DogResult<HttpResponse> {
-        let script = self.scripts.get(script).unwrap();
+    pub fn run_script(
+        &self,
+        script: &str,
+        request: HttpRequest,
+        limits: &ScriptLimits,
+    ) -> DogResult<HttpResponse> {
+        // Reuse an idle state or set up another one if all are busy
+        let state = self.states.lock().unwrap().pop();
+        let mut state = match state {
+            Some(state) => state,
+            None => self.new_state()?,
+        };
+        self.refresh(&mut state)?;
+        let script = state.scripts.get(script).unwrap();
+
+        let timed_out = Arc::new(AtomicBool::new(false));
+        if let Some(timeout) = limits.timeout {
+            let limit = TimeLimit {
+                deadline: Instant::now() + timeout,
+                timed_out: timed_out.clone(),
+            };
+            limit.hook(&state.lua.current_thread());
+            state.lua.set_app_data(limit);
+        }
+        if let Some(memory) = limits.memory {
+            let _ = state.lua.set_memory_limit(memory);
+        }
+        if let Some(sessions) = &self.sessions {
+            let cookie = request.headers.get_all("Cookie").join("; ");
+            state
+                .lua
+                .set_app_data(Session::new(sessions.clone(), Some(&cookie)));
+        }
         let result = script.run(request);
-        if result.is_err() {
-            return Err(DogError::new(
+        let session = state.lua.remove_app_data::<Session>();
+        if let Some(mut uploads) = state.lua.app_data_mut::<TempUploads>() {
+            for path in uploads.0.drain(..) {
+                let _ = fs::remove_file(path);
+            }
+        }
+        state.lua.remove_hook();
+        state.lua.remove_app_data::<TimeLimit>();
+        let _ = state.lua.set_memory_limit(0);
+
+        let response = match result {
+            // The script may have caught the timeout error with `pcall`
+            _ if timed_out.load(Ordering::SeqCst) => Err(DogError::new(
                 &self.logger,
-                "usr-script-run".to_string(),
+                "usr-script-timeout".to_string(),
                 format!(
-                    "Running script ({}) failed => {}",
+                    "Script ({}) exceeded its time limit of {:?}",
                     script.path,
-                    result.unwrap_err()
+                    limits.timeout.unwrap_or_default()
                 ),
-            ));
+            )),
+            Ok(table) => self.table_to_response(table).and_then(|mut response| {
+                // Sessions are only written back for scripts which succeeded
+                let cookie = match session.map(|t| t.save()) {
+                    Some(Ok(cookie)) => cookie,
+                    Some(Err(e)) => {
+                        return Err(DogError::new(
+                            &self.logger,
+                            "usr-session-save".to_string(),
+                            format!(
+                                "Could not save session of script ({}) => {}",
+                                script.path, e
+                            ),
+                        ))
+                    }
+                    None => None,
+                };
+                if let Some(cookie) = cookie {
+                    let _ = response.append_header("Set-Cookie", &cookie);
+                }
+                Ok(response)
+            }),
+            // Answered like a client error of netpup itself, with the error routes
+            Err(e) if client_error(&e).is_some() => {
+                let error = client_error(&e).unwrap();
+                Ok(HttpResponse::new(
+                    (error.erc, error.details.clone()),
+                    Headers::new(),
+                    (vec![], "".to_string()),
+                    true,
+                ))
+            }
+            Err(e) if is_memory_error(&e) => {
+                let _ = state.lua.gc_collect();
+                Err(DogError::new(
+                    &self.logger,
+                    "usr-script-memory".to_string(),
+                    format!(
+                        "Script ({}) exceeded its memory limit of {} bytes",
+                        script.path,
+                        limits.memory.unwrap_or_default()
+                    ),
+                ))
+            }
+            Err(e) => Err(DogError::new(
+                &self.logger,
+                "usr-script-run".to_string(),
+                format!("Running script ({}) failed => {}", script.path, e),
+            )),
+        };
+        // A panicking run never gets here, so its state is dropped
+        self.states.lock().unwrap().push(state);
+        response
+    }
+
+    /// Runs the hooks scripts registered with `on_shutdown` in each idle state.
+    pub fn shutdown(&self) {
+        let states = std::mem::take(&mut *self.states.lock().unwrap());
+        for state in states {
+            let hooks: Table = match state.lua.named_registry_value(SHUTDOWN_HOOKS) {
+                Ok(hooks) => hooks,
+                Err(_) => continue,
+            };
+            for (source, hook) in hooks.pairs::<String, Function>().flatten() {
+                if let Err(e) = hook.call::<()>(()) {
+                    DogError::new(
+                        &self.logger,
+                        "usr-script-shutdown".to_string(),
+                        format!(
+                            "Shutdown hook of script ({}) failed => {}",
+                            source.trim_start_matches('@'),
+                            e
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_over_the_memory_limit_raise_lua_errors() {
+        let dir = std::env::temp_dir().join(format!("netpup-script-read-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("big.txt");
+        fs::write(&file, "x".repeat(1 << 20)).unwrap();
+        let logger = Logger::new(false, None).unwrap();
+        let sandbox = FileSandbox::new(&logger, &[dir.to_string_lossy().to_string()], &[]).unwrap();
+        let path = fs::canonicalize(&file)
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+
+        let lua = Lua::new();
+        lua.set_memory_limit(lua.used_memory() + (1 << 16)).unwrap();
+        assert!(_lua_read(&lua, &sandbox, path.clone()).is_err());
+        assert!(_lua_read_bytes(&lua, &sandbox, path.clone()).is_err());
+        lua.set_memory_limit(0).unwrap();
+        assert_eq!(
+            _lua_read(&lua, &sandbox, path).unwrap().as_bytes().len(),
+            1 << 20
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    /// Runs `code` with a time limit which is already up.
+    fn run_out_of_time(code: &str) -> mlua::Result<String> {
+        let lua = Lua::new();
+        guard_timeouts(&lua).unwrap();
+        let limit = TimeLimit {
+            deadline: Instant::now(),
+            timed_out: Arc::new(AtomicBool::new(false)),
+        };
+        limit.hook(&lua.current_thread());
+        lua.set_app_data(limit);
+        lua.load(code).eval()
+    }
+
+    #[test]
+    fn timeouts_can_not_be_caught() {
+        let escapes = [
+            "pcall(function() for i = 1, 1e8 do end end)",
+            "xpcall(function() for i = 1, 1e8 do end end, function(e) return e end)",
+            "pcall(pcall, function() for i = 1, 1e8 do end end)",
+            // Coroutines are hooked as well and do not swallow the error
+            "coroutine.resume(coroutine.create(function() for i = 1, 1e8 do end end))",
+            "pcall(coroutine.wrap(function() for i = 1, 1e8 do end end))",
+            "local co = coroutine.create(function() for i = 1, 1e8 do end end)
+             pcall(coroutine.resume, co)
+             coroutine.close(co)",
+            "local co = coroutine.create(function()
+                 coroutine.yield()
+                 for i = 1, 1e8 do end
+             end)
+             coroutine.resume(co)
+             coroutine.resume(co)",
+        ];
+        for code in escapes {
+            let result = run_out_of_time(&format!("{}\nreturn 'escaped'", code));
+            assert!(
+                result.as_ref().is_err_and(is_timeout),
+                "{}: {:?}",
+                code,
+                result
+            );
         }
-        Ok(self.table_to_response(result.unwrap())?)
+    }
+
+    #[test]
+    fn other_errors_are_still_caught() {
+        let lua = Lua::new();
+        guard_timeouts(&lua).unwrap();
+        let caught: (bool, bool, bool) = lua
+            .load(
+                "local a = pcall(error, 'netpup: script timed out')
+                 local b = coroutine.resume(coroutine.create(function() error({}) end))
+                 local c = pcall(coroutine.wrap(function() error('x') end))
+                 return a, b, c",
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(caught, (false, false, false));
+        let values: (i64, i64) = lua
+            .load(
+                "local next = coroutine.wrap(function() coroutine.yield(1) return 2 end)
+                 return next(), next()",
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(values, (1, 2));
     }
 }
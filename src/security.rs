@@ -0,0 +1,443 @@
+use crate::errors::{DogError, DogResult};
+use crate::headers::Headers;
+use crate::logger::Logger;
+use base64::prelude::*;
+use toml::Value;
+
+const STRICT_CSP: &str = "default-src 'self'; object-src 'none'; base-uri 'self'";
+
+/// Security related response headers, applied to every response.
+/// Headers set by routes or scripts take precedence.
+#[derive(Clone, Debug, Default)]
+pub struct SecurityHeaders {
+    hsts: Option<String>,
+    content_type_options: bool,
+    referrer_policy: Option<String>,
+    frame_options: Option<String>,
+    csp: Option<String>,
+    csp_nonce: bool,
+}
+
+impl SecurityHeaders {
+    fn preset(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Self::default()),
+            "basic" => Some(Self {
+                content_type_options: true,
+                referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+                frame_options: Some("SAMEORIGIN".to_string()),
+                ..Self::default()
+            }),
+            "strict" => Some(Self {
+                hsts: Some("max-age=31536000; includeSubDomains".to_string()),
+                content_type_options: true,
+                referrer_policy: Some("no-referrer".to_string()),
+                frame_options: Some("DENY".to_string()),
+                csp: Some(STRICT_CSP.to_string()),
+                csp_nonce: false,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Loads `security_headers = "strict"` or a table of a `preset` and overrides.
+    pub fn new(logger: &Logger, value: &Value) -> DogResult<Self> {
+        let ill_formatted = |key: &str| {
+            DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                format!("Ill formatted key '{}'", key),
+            )
+        };
+        let t = match value {
+            Value::String(preset) => {
+                return Self::preset(preset).ok_or_else(|| ill_formatted("security_headers"))
+            }
+            Value::Table(t) => t,
+            _ => return Err(ill_formatted("security_headers")),
+        };
+
+        let mut security = match t.get("preset") {
+            Some(Value::String(preset)) => Self::preset(preset),
+            None => Self::preset("basic"),
+            Some(_) => None,
+        }
+        .ok_or_else(|| ill_formatted("security_headers.preset"))?;
+        // Header values can be replaced by a string or removed with `false`
+        let get_value = |key: &str, name: &str, current: Option<String>| match t.get(key) {
+            None => Ok(current),
+            Some(Value::Boolean(false)) => Ok(None),
+            Some(Value::String(value)) if Headers::validate(name, value).is_ok() => {
+                Ok(Some(value.clone()))
+            }
+            Some(_) => Err(ill_formatted(&format!("security_headers.{}", key))),
+        };
+        let get_bool = |key: &str, current: bool| match t.get(key) {
+            None => Ok(current),
+            Some(Value::Boolean(value)) => Ok(*value),
+            Some(_) => Err(ill_formatted(&format!("security_headers.{}", key))),
+        };
+
+        security.hsts = get_value("hsts", "Strict-Transport-Security", security.hsts)?;
+        security.content_type_options =
+            get_bool("content_type_options", security.content_type_options)?;
+        security.referrer_policy = get_value(
+            "referrer_policy",
+            "Referrer-Policy",
+            security.referrer_policy,
+        )?;
+        security.frame_options =
+            get_value("frame_options", "X-Frame-Options", security.frame_options)?
+                .map(|t1| t1.to_uppercase());
+        if security
+            .frame_options
+            .as_ref()
+            .is_some_and(|t1| t1 != "DENY" && t1 != "SAMEORIGIN")
+        {
+            return Err(ill_formatted("security_headers.frame_options"));
+        }
+        security.csp = get_value("csp", "Content-Security-Policy", security.csp)?;
+        security.csp_nonce = get_bool("csp_nonce", security.csp_nonce)?;
+        if security.csp_nonce && security.csp.is_none() {
+            return Err(DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                "Key 'security_headers.csp_nonce' needs a 'csp'".to_string(),
+            ));
+        }
+        Ok(security)
+    }
+
+    /// Generates a fresh nonce if inline scripts are allowed by nonce.
+    pub fn new_nonce(&self) -> Option<String> {
+        if !self.csp_nonce {
+            return None;
+        }
+        let mut bytes = [0u8; 16];
+        getrandom::fill(&mut bytes).ok()?;
+        Some(BASE64_STANDARD.encode(bytes))
+    }
+
+    /// Builds the Content-Security-Policy, adding `frame-ancestors` matching `X-Frame-Options`
+    /// and the nonce to the `script-src` and `style-src` directives.
+    fn content_security_policy(&self, csp: &str, nonce: Option<&str>) -> String {
+        let mut directives: Vec<String> = csp
+            .split(';')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+        let has = |directives: &[String], name: &str| {
+            directives
+                .iter()
+                .any(|t| t.split_whitespace().next() == Some(name))
+        };
+
+        if let Some(frame_options) = &self.frame_options {
+            if !has(&directives, "frame-ancestors") {
+                directives.push(match frame_options.as_str() {
+                    "DENY" => "frame-ancestors 'none'".to_string(),
+                    _ => "frame-ancestors 'self'".to_string(),
+                });
+            }
+        }
+        if let Some(nonce) = nonce {
+            let source = format!("'nonce-{}'", nonce);
+            if !has(&directives, "script-src") {
+                // Scripts fall back to `default-src`, which has to be kept
+                let default = directives
+                    .iter()
+                    .find_map(|t| t.strip_prefix("default-src"))
+                    .map(|t| t.replace("'none'", "").trim().to_string())
+                    .unwrap_or_else(|| "'self'".to_string());
+                directives.push(format!("script-src {}", default).trim().to_string());
+            }
+            for directive in directives.iter_mut() {
+                let name = directive.split_whitespace().next().unwrap_or("");
+                if name == "script-src" || name == "style-src" {
+                    *directive = directive.replace("'none'", "").trim().to_string();
+                    *directive += format!(" {}", source).as_str();
+                }
+            }
+        }
+        directives.join("; ")
+    }
+
+    pub fn headers(&self, nonce: Option<&str>) -> Headers {
+        let mut headers = Headers::new();
+        if let Some(hsts) = &self.hsts {
+            let _ = headers.append("Strict-Transport-Security", hsts);
+        }
+        if self.content_type_options {
+            let _ = headers.append("X-Content-Type-Options", "nosniff");
+        }
+        if let Some(referrer_policy) = &self.referrer_policy {
+            let _ = headers.append("Referrer-Policy", referrer_policy);
+        }
+        if let Some(frame_options) = &self.frame_options {
+            let _ = headers.append("X-Frame-Options", frame_options);
+        }
+        if let Some(csp) = &self.csp {
+            let _ = headers.append(
+                "Content-Security-Policy",
+                &self.content_security_policy(csp, nonce),
+            );
+        }
+        headers
+    }
+}
+
+/// Elements whose content is text up to their end tag, where `<script>` is no tag.
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "textarea", "title"];
+
+/// Finds `needle` in `haystack` from `from` on.
+fn find(haystack: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|t| t.eq_ignore_ascii_case(needle))
+        .map(|t| t + from)
+}
+
+/// Reads the tag name and attributes of the start tag at `start` (pointing at `<`).
+/// Returns the lowercase name, the attribute names and the position after the name and the tag,
+/// or `None` if the tag is not closed.
+fn parse_start_tag(html: &[u8], start: usize) -> Option<(String, Vec<String>, usize, usize)> {
+    let is_space = |t: u8| t.is_ascii_whitespace();
+    let mut pos = start + 1;
+    while pos < html.len() && !is_space(html[pos]) && html[pos] != b'>' && html[pos] != b'/' {
+        pos += 1;
+    }
+    let name = String::from_utf8_lossy(&html[start + 1..pos]).to_lowercase();
+    let name_end = pos;
+
+    let mut attributes = vec![];
+    loop {
+        while pos < html.len() && (is_space(html[pos]) || html[pos] == b'/') {
+            pos += 1;
+        }
+        if *html.get(pos)? == b'>' {
+            return Some((name, attributes, name_end, pos + 1));
+        }
+        let attribute_start = pos;
+        while pos < html.len() && !is_space(html[pos]) && !b"=>/".contains(&html[pos]) {
+            pos += 1;
+        }
+        // A lone `=` or `/` still moves on
+        pos = pos.max(attribute_start + 1);
+        attributes.push(String::from_utf8_lossy(&html[attribute_start..pos]).to_lowercase());
+        while pos < html.len() && is_space(html[pos]) {
+            pos += 1;
+        }
+        if html.get(pos) != Some(&b'=') {
+            continue;
+        }
+        pos += 1;
+        while pos < html.len() && is_space(html[pos]) {
+            pos += 1;
+        }
+        match html.get(pos)? {
+            quote @ (b'"' | b'\'') => {
+                pos = html[pos + 1..].iter().position(|t| t == quote)? + pos + 2;
+            }
+            _ => {
+                while pos < html.len() && !is_space(html[pos]) && html[pos] != b'>' {
+                    pos += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Adds `nonce="..."` to every `<script>` and `<style>` start tag of an HTML document
+/// which has no nonce yet. Comments, attribute values and the content of raw text
+/// elements like scripts themselves are skipped, so tags in there stay untouched.
+pub fn add_nonce(html: &[u8], nonce: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(html.len() + 64);
+    let mut pos = 0;
+    while let Some(offset) = html[pos..].iter().position(|t| *t == b'<') {
+        let start = pos + offset;
+        out.extend_from_slice(&html[pos..start]);
+        let rest = &html[start..];
+        let end = if rest.starts_with(b"<!--") {
+            find(html, start + 4, b"-->").map_or(html.len(), |t| t + 3)
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"<?") {
+            find(html, start, b">").map_or(html.len(), |t| t + 1)
+        } else if rest.get(1).is_some_and(|t| t.is_ascii_alphabetic()) {
+            match parse_start_tag(html, start) {
+                Some((name, attributes, name_end, tag_end)) => {
+                    out.extend_from_slice(&html[start..name_end]);
+                    if (name == "script" || name == "style")
+                        && !attributes.iter().any(|t| t == "nonce")
+                    {
+                        out.extend_from_slice(format!(" nonce=\"{}\"", nonce).as_bytes());
+                    }
+                    out.extend_from_slice(&html[name_end..tag_end]);
+                    pos = tag_end;
+                    if RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+                        let close = format!("</{}", name);
+                        let text_end = find(html, pos, close.as_bytes()).unwrap_or(html.len());
+                        out.extend_from_slice(&html[pos..text_end]);
+                        pos = text_end;
+                    }
+                    continue;
+                }
+                None => html.len(),
+            }
+        } else {
+            start + 1
+        };
+        out.extend_from_slice(&html[start..end]);
+        pos = end;
+    }
+    out.extend_from_slice(&html[pos..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn security(settings: &str) -> DogResult<SecurityHeaders> {
+        let t: toml::Table = settings.parse().unwrap();
+        SecurityHeaders::new(&Logger::new(false, None).unwrap(), &Value::Table(t))
+    }
+
+    fn csp(security: &SecurityHeaders, nonce: Option<&str>) -> String {
+        security
+            .headers(nonce)
+            .get("Content-Security-Policy")
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn presets() {
+        let logger = Logger::new(false, None).unwrap();
+        let off = SecurityHeaders::new(&logger, &Value::from("off")).unwrap();
+        assert_eq!(off.headers(None).iter().count(), 0);
+
+        let basic = SecurityHeaders::new(&logger, &Value::from("basic")).unwrap();
+        let headers = basic.headers(None);
+        assert_eq!(headers.get("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(headers.get("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(headers.get("Content-Security-Policy"), None);
+
+        let strict = SecurityHeaders::new(&logger, &Value::from("strict")).unwrap();
+        assert_eq!(
+            csp(&strict, None),
+            "default-src 'self'; object-src 'none'; base-uri 'self'; frame-ancestors 'none'"
+        );
+        assert!(SecurityHeaders::new(&logger, &Value::from("paranoid")).is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let strict =
+            security("preset = \"strict\"\nhsts = false\nframe_options = \"sameorigin\"").unwrap();
+        let headers = strict.headers(None);
+        assert_eq!(headers.get("Strict-Transport-Security"), None);
+        assert_eq!(headers.get("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert!(csp(&strict, None).ends_with("frame-ancestors 'self'"));
+
+        assert!(security("frame_options = \"ALLOW-FROM x\"").is_err());
+        assert!(security("referrer_policy = \"a\\r\\nb\"").is_err());
+        assert!(security("csp_nonce = true").is_err());
+        assert!(security("preset = 1").is_err());
+    }
+
+    #[test]
+    fn nonces_are_fresh() {
+        let enabled = security("csp = \"default-src 'self'\"\ncsp_nonce = true").unwrap();
+        let first = enabled.new_nonce().unwrap();
+        let second = enabled.new_nonce().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(BASE64_STANDARD.decode(&first).unwrap().len(), 16);
+
+        let without = security("csp = \"default-src 'self'\"").unwrap();
+        assert_eq!(without.new_nonce(), None);
+    }
+
+    #[test]
+    fn nonce_is_added_to_script_and_style_sources() {
+        let defaults =
+            security("csp = \"default-src 'none'; style-src 'self'; img-src *\"\ncsp_nonce = true")
+                .unwrap();
+        assert_eq!(
+            csp(&defaults, Some("abc")),
+            "default-src 'none'; style-src 'self' 'nonce-abc'; img-src *; \
+             frame-ancestors 'self'; script-src 'nonce-abc'"
+        );
+
+        let explicit = security(
+            "csp = \"script-src 'none'; frame-ancestors 'none'\"\nframe_options = false\ncsp_nonce = true",
+        )
+        .unwrap();
+        assert_eq!(
+            csp(&explicit, Some("abc")),
+            "script-src 'nonce-abc'; frame-ancestors 'none'"
+        );
+        // Without a nonce for the response the policy is left alone
+        assert_eq!(
+            csp(&explicit, None),
+            "script-src 'none'; frame-ancestors 'none'"
+        );
+    }
+
+    fn nonced(html: &str) -> String {
+        String::from_utf8(add_nonce(html.as_bytes(), "abc")).unwrap()
+    }
+
+    #[test]
+    fn nonce_is_added_to_every_script_and_style_tag() {
+        assert_eq!(
+            nonced("<p>a</p><script>go()</script><STYLE>p{}</STYLE>"),
+            "<p>a</p><script nonce=\"abc\">go()</script><STYLE nonce=\"abc\">p{}</STYLE>"
+        );
+        assert_eq!(
+            nonced("<script src=\"/app.js\" defer></script><style\nmedia=print>p{}</style>"),
+            "<script nonce=\"abc\" src=\"/app.js\" defer></script>\
+             <style nonce=\"abc\"\nmedia=print>p{}</style>"
+        );
+        assert_eq!(
+            nonced("<script type='module' async/>m()</script>"),
+            "<script nonce=\"abc\" type='module' async/>m()</script>"
+        );
+        // Existing nonces are kept, similar names are no script tags
+        assert_eq!(
+            nonced("<script NONCE=\"x\"></script><scripts></scripts><stylesheet>"),
+            "<script NONCE=\"x\"></script><scripts></scripts><stylesheet>"
+        );
+    }
+
+    #[test]
+    fn nonce_is_not_added_inside_comments_and_strings() {
+        let untouched = [
+            "<!-- <script>old()</script> -->",
+            "<a title=\"<script>\" href='<style>'>x</a>",
+            "<p data-x=<script>>",
+            "<textarea><script>x</script></textarea>",
+            "<!DOCTYPE html><?xml version=\"1.0\"?>",
+            "1 < 2 <3 <",
+            "<div class=\"unterminated <script>",
+            "<!-- unterminated <script>",
+        ];
+        for html in untouched {
+            assert_eq!(nonced(html), html);
+        }
+        // Scripts are raw text up to their end tag
+        assert_eq!(
+            nonced("<script>let s = \"<script>\" + '<style>';</script><style>a::after{content:\"<script>\"}</style>"),
+            "<script nonce=\"abc\">let s = \"<script>\" + '<style>';</script>\
+             <style nonce=\"abc\">a::after{content:\"<script>\"}</style>"
+        );
+        assert_eq!(
+            nonced("<!-- a --><script>1</script><!----><script>2</SCRIPT><script>3</script>"),
+            "<!-- a --><script nonce=\"abc\">1</script><!---->\
+             <script nonce=\"abc\">2</SCRIPT><script nonce=\"abc\">3</script>"
+        );
+        assert_eq!(
+            nonced("héllo <script>ü</script>"),
+            "héllo <script nonce=\"abc\">ü</script>"
+        );
+    }
+}
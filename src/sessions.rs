@@ -0,0 +1,819 @@
+use crate::cookies::{self, SameSite, SetCookie};
+use base64::prelude::*;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Browsers drop cookies above this size.
+const MAX_COOKIE_SIZE: usize = 4096;
+
+/// Expired sessions of the memory and file store are swept every that many saves.
+const SWEEP_INTERVAL: usize = 100;
+
+/// Size of the random XChaCha20-Poly1305 nonce, large enough to never repeat by chance.
+const NONCE_SIZE: usize = 24;
+
+/// Keys derived from one secret, so signing and encryption never share a key.
+#[derive(Clone)]
+struct Keys {
+    sign: [u8; 32],
+    encrypt: [u8; 32],
+}
+
+impl Keys {
+    fn derive(secret: &str) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, secret.as_bytes());
+        let mut keys = Self {
+            sign: [0; 32],
+            encrypt: [0; 32],
+        };
+        // 32 bytes are always a valid output length for SHA-256
+        hkdf.expand(b"netpup session sign", &mut keys.sign).unwrap();
+        hkdf.expand(b"netpup session encrypt", &mut keys.encrypt)
+            .unwrap();
+        keys
+    }
+
+    fn tag(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.sign).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.sign).unwrap();
+        mac.update(data);
+        mac.verify_slice(tag).is_ok()
+    }
+
+    /// Encrypts and authenticates `data` with XChaCha20-Poly1305, prepending the random nonce.
+    fn seal(&self, aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new(&self.encrypt.into());
+        let mut nonce = [0u8; NONCE_SIZE];
+        getrandom::fill(&mut nonce).ok()?;
+        let sealed = cipher
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: data, aad })
+            .ok()?;
+        Some([&nonce[..], &sealed].concat())
+    }
+
+    /// Decrypts what `seal` made, `None` if it was not sealed with this key or changed since.
+    fn open(&self, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, data) = sealed.split_at(NONCE_SIZE);
+        let cipher = XChaCha20Poly1305::new(&self.encrypt.into());
+        cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: data, aad })
+            .ok()
+    }
+}
+
+impl std::fmt::Debug for Keys {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Keys(..)")
+    }
+}
+
+/// A stored session, `expires` in Unix time.
+#[derive(Clone, Debug)]
+pub struct Record {
+    expires: i64,
+    data: Map<String, Value>,
+}
+
+impl Record {
+    fn to_json(&self) -> Vec<u8> {
+        json!({ "exp": self.expires, "data": self.data })
+            .to_string()
+            .into_bytes()
+    }
+
+    fn from_json(bytes: &[u8]) -> Option<Self> {
+        let value: Value = serde_json::from_slice(bytes).ok()?;
+        let record = Self {
+            expires: value.get("exp")?.as_i64()?,
+            data: value.get("data")?.as_object()?.clone(),
+        };
+        (record.expires > now()).then_some(record)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Store {
+    /// The session data lives in the cookie itself, signed or encrypted
+    Cookie { encrypt: bool },
+    /// The cookie holds a session ID, the data stays in memory and is lost on restart
+    Memory(Arc<Mutex<HashMap<String, Record>>>),
+    /// The cookie holds a session ID, the data is kept in one file per session
+    File(PathBuf),
+}
+
+impl Store {
+    pub fn memory() -> Self {
+        Store::Memory(Arc::new(Mutex::new(HashMap::new())))
+    }
+}
+
+/// Session settings and storage, configured in `[sessions]` and shared by all Lua states.
+#[derive(Clone, Debug)]
+pub struct Sessions {
+    /// The first key signs, all of them verify, so secrets can be rotated
+    keys: Vec<Keys>,
+    store: Store,
+    pub cookie_name: String,
+    /// Seconds a session lives after it was last saved
+    pub max_age: i64,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+    /// Sessions the memory and file store keep at most, the ones expiring first make room for new ones
+    pub max_sessions: usize,
+    saves: Arc<AtomicUsize>,
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// Removes expired records, then the ones expiring first until at most `keep` are left.
+fn sweep_memory(records: &mut HashMap<String, Record>, keep: usize) {
+    let now = now();
+    records.retain(|_, t| t.expires > now);
+    while records.len() > keep {
+        let oldest = records
+            .iter()
+            .min_by_key(|(_, t)| t.expires)
+            .map(|(id, _)| id.clone());
+        match oldest {
+            Some(id) => records.remove(&id),
+            None => break,
+        };
+    }
+}
+
+/// Session files are named `<ID>.json`, with IDs of 64 lowercase hex digits.
+fn is_session_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|t| t.to_str())
+        .and_then(|t| t.strip_suffix(".json"))
+        .is_some_and(|id| {
+            id.len() == 64 && id.bytes().all(|t| matches!(t, b'0'..=b'9' | b'a'..=b'f'))
+        })
+}
+
+/// The session files in `dir`, other files are never touched.
+fn session_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|t| t.ok())
+                .map(|t| t.path())
+                .filter(|t| is_session_file(t))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Like `sweep_memory` for the session files in `dir`, unreadable ones count as expired.
+fn sweep_dir(dir: &Path, keep: usize) {
+    let mut files = vec![];
+    for path in session_files(dir) {
+        match fs::read(&path).ok().and_then(|t| Record::from_json(&t)) {
+            Some(record) => files.push((record.expires, path)),
+            None => {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+    if files.len() > keep {
+        files.sort();
+        for (_, path) in files.drain(..files.len() - keep) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn random_hex(len: usize) -> Option<String> {
+    let mut bytes = vec![0u8; len];
+    getrandom::fill(&mut bytes).ok()?;
+    Some(bytes.iter().map(|t| format!("{:02x}", t)).collect())
+}
+
+impl Sessions {
+    pub fn new(secrets: &[String], store: Store, cookie_name: String, max_age: i64) -> Self {
+        Self {
+            keys: secrets.iter().map(|t| Keys::derive(t)).collect(),
+            store,
+            cookie_name,
+            max_age,
+            secure: false,
+            http_only: true,
+            same_site: Some(SameSite::Lax),
+            max_sessions: 10_000,
+            saves: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(self.keys[0].tag(data))
+    }
+
+    /// Checks the tag against all keys, returning the one which signed `data`.
+    fn verified(&self, data: &[u8], tag: &str) -> Option<&Keys> {
+        let tag = BASE64_URL_SAFE_NO_PAD.decode(tag).ok()?;
+        self.keys.iter().find(|t| t.verify(data, &tag))
+    }
+
+    /// Reads the session a request cookie points to. Forged, expired or unknown sessions are `None`.
+    fn load(&self, cookie: &str) -> Option<(Option<String>, Record)> {
+        match &self.store {
+            Store::Cookie { .. } => {
+                // Signed cookies are `s.<data>.<tag>`, encrypted ones `e.<nonce and ciphertext>`
+                let body = match cookie.split_once('.')? {
+                    ("s", rest) => {
+                        let (body, tag) = rest.split_once('.')?;
+                        self.verified(format!("s.{}", body).as_bytes(), tag)?;
+                        BASE64_URL_SAFE_NO_PAD.decode(body).ok()?
+                    }
+                    ("e", body) => {
+                        let sealed = BASE64_URL_SAFE_NO_PAD.decode(body).ok()?;
+                        self.keys.iter().find_map(|t| t.open(b"e", &sealed))?
+                    }
+                    _ => return None,
+                };
+                Record::from_json(&body).map(|t| (None, t))
+            }
+            Store::Memory(records) => {
+                let (id, tag) = cookie.split_once('.')?;
+                self.verified(id.as_bytes(), tag)?;
+                let record = records.lock().unwrap().get(id)?.clone();
+                (record.expires > now()).then(|| (Some(id.to_string()), record))
+            }
+            Store::File(dir) => {
+                let (id, tag) = cookie.split_once('.')?;
+                self.verified(id.as_bytes(), tag)?;
+                // Signed IDs are hex, so they can not point out of `dir`
+                let path = dir.join(format!("{}.json", id));
+                let record = Record::from_json(&fs::read(&path).ok()?);
+                if record.is_none() {
+                    let _ = fs::remove_file(&path);
+                }
+                record.map(|t| (Some(id.to_string()), t))
+            }
+        }
+    }
+
+    fn remove(&self, id: &str) {
+        match &self.store {
+            Store::Cookie { .. } => {}
+            Store::Memory(records) => {
+                records.lock().unwrap().remove(id);
+            }
+            Store::File(dir) => {
+                let _ = fs::remove_file(dir.join(format!("{}.json", id)));
+            }
+        }
+    }
+
+    /// Stores a session and returns the cookie value pointing to it.
+    fn store(&self, id: Option<&str>, record: &Record) -> Result<String, String> {
+        let new_id = || random_hex(32).ok_or_else(|| "Unable to create a session ID".to_string());
+        let sweep = self
+            .saves
+            .fetch_add(1, Ordering::SeqCst)
+            .is_multiple_of(SWEEP_INTERVAL);
+        match &self.store {
+            Store::Cookie { encrypt: true } => {
+                let sealed = self.keys[0]
+                    .seal(b"e", &record.to_json())
+                    .ok_or_else(|| "Unable to encrypt session".to_string())?;
+                Ok(format!("e.{}", BASE64_URL_SAFE_NO_PAD.encode(sealed)))
+            }
+            Store::Cookie { encrypt: false } => {
+                let signed = format!("s.{}", BASE64_URL_SAFE_NO_PAD.encode(record.to_json()));
+                Ok(format!("{}.{}", signed, self.sign(signed.as_bytes())))
+            }
+            Store::Memory(records) => {
+                let id = id.map_or_else(new_id, |t| Ok(t.to_string()))?;
+                let mut records = records.lock().unwrap();
+                let is_new = !records.contains_key(&id);
+                if sweep || (is_new && records.len() >= self.max_sessions) {
+                    sweep_memory(&mut records, self.max_sessions - usize::from(is_new));
+                }
+                records.insert(id.clone(), record.clone());
+                Ok(format!("{}.{}", id, self.sign(id.as_bytes())))
+            }
+            Store::File(dir) => {
+                let id = id.map_or_else(new_id, |t| Ok(t.to_string()))?;
+                // Written next to the target and renamed, so readers never see half a session
+                let path = dir.join(format!("{}.json", id));
+                let is_new = !path.exists();
+                let full = is_new && session_files(dir).len() >= self.max_sessions;
+                if sweep || full {
+                    sweep_dir(dir, self.max_sessions - usize::from(is_new));
+                }
+                // Concurrent saves of the same session each write a temporary file of their own
+                let suffix =
+                    random_hex(8).ok_or_else(|| "Unable to create a session file".to_string())?;
+                let temp = dir.join(format!("{}.{}.tmp", id, suffix));
+                fs::write(&temp, record.to_json())
+                    .and_then(|_| fs::rename(&temp, &path))
+                    .map_err(|_e| {
+                        let _ = fs::remove_file(&temp);
+                        "Unable to write session file".to_string()
+                    })?;
+                Ok(format!("{}.{}", id, self.sign(id.as_bytes())))
+            }
+        }
+    }
+
+    fn cookie(&self, value: &str) -> SetCookie {
+        SetCookie {
+            path: Some("/".to_string()),
+            max_age: Some(self.max_age),
+            secure: self.secure,
+            http_only: self.http_only,
+            same_site: self.same_site,
+            ..SetCookie::new(&self.cookie_name, value)
+        }
+    }
+}
+
+/// The session of the request a script is running for.
+/// It is read on first use and only written back if the script changed it.
+#[derive(Debug)]
+pub struct Session {
+    sessions: Arc<Sessions>,
+    cookie: Option<String>,
+    loaded: bool,
+    id: Option<String>,
+    data: Map<String, Value>,
+    changed: bool,
+    destroyed: bool,
+    rotate: bool,
+}
+
+impl Session {
+    pub fn new(sessions: Arc<Sessions>, cookie_header: Option<&str>) -> Self {
+        let cookie = cookie_header.and_then(|header| {
+            cookies::parse(header)
+                .into_iter()
+                .find(|t| t.0 == sessions.cookie_name)
+                .map(|t| t.1)
+        });
+        Self {
+            sessions,
+            cookie,
+            loaded: false,
+            id: None,
+            data: Map::new(),
+            changed: false,
+            destroyed: false,
+            rotate: false,
+        }
+    }
+
+    fn load(&mut self) {
+        if self.loaded {
+            return;
+        }
+        self.loaded = true;
+        if let Some((id, record)) = self.cookie.as_ref().and_then(|t| self.sessions.load(t)) {
+            self.id = id;
+            self.data = record.data;
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&Value> {
+        self.load();
+        self.data.get(key)
+    }
+
+    /// Sets a value, `Null` removes it.
+    pub fn set(&mut self, key: &str, value: Value) {
+        self.load();
+        match value {
+            Value::Null => self.data.remove(key),
+            value => self.data.insert(key.to_string(), value),
+        };
+        // Data set after a destroy starts a new session instead of reviving the old ID
+        if self.destroyed {
+            self.rotate = true;
+            self.destroyed = false;
+        }
+        self.changed = true;
+    }
+
+    /// Drops all data and the session cookie.
+    pub fn destroy(&mut self) {
+        self.load();
+        self.data.clear();
+        self.destroyed = true;
+        self.changed = false;
+    }
+
+    /// Moves the data to a new session ID, e.g. after a login so a planted ID becomes worthless.
+    pub fn rotate(&mut self) {
+        self.load();
+        self.rotate = true;
+        self.changed = true;
+    }
+
+    /// Writes a changed session back and returns the `Set-Cookie` header value for the response.
+    pub fn save(mut self) -> Result<Option<String>, String> {
+        let sessions = self.sessions.clone();
+        if self.destroyed {
+            if let Some(id) = &self.id {
+                sessions.remove(id);
+            }
+            if self.cookie.is_none() {
+                return Ok(None);
+            }
+            let removal = SetCookie {
+                path: Some("/".to_string()),
+                ..SetCookie::removal(&sessions.cookie_name)
+            };
+            return removal.build().map(Some);
+        }
+        if !self.changed {
+            return Ok(None);
+        }
+        if self.rotate {
+            if let Some(id) = self.id.take() {
+                sessions.remove(&id);
+            }
+        }
+        let record = Record {
+            expires: now() + sessions.max_age,
+            data: std::mem::take(&mut self.data),
+        };
+        let value = sessions.store(self.id.as_deref(), &record)?;
+        let cookie = sessions.cookie(&value).build()?;
+        if cookie.len() > MAX_COOKIE_SIZE {
+            return Err("Session too large for a cookie".to_string());
+        }
+        Ok(Some(cookie))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef-current";
+    const OLD_SECRET: &str = "0123456789abcdef0123456789abcdef-previous";
+
+    fn sessions(secrets: &[&str], store: Store) -> Arc<Sessions> {
+        let secrets: Vec<String> = secrets.iter().map(|t| t.to_string()).collect();
+        Arc::new(Sessions::new(&secrets, store, "sid".to_string(), 3600))
+    }
+
+    fn cookie_sessions(secrets: &[&str], encrypt: bool) -> Arc<Sessions> {
+        sessions(secrets, Store::Cookie { encrypt })
+    }
+
+    fn record(expires: i64, key: &str) -> Record {
+        let mut data = Map::new();
+        data.insert(key.to_string(), json!(true));
+        Record { expires, data }
+    }
+
+    /// Saves `value` under `key` in a session started from `cookie`, returning the new cookie value.
+    fn save(sessions: &Arc<Sessions>, cookie: Option<&str>, key: &str, value: Value) -> String {
+        let header = cookie.map(|t| format!("sid={}", t));
+        let mut session = Session::new(sessions.clone(), header.as_deref());
+        session.set(key, value);
+        let set_cookie = session.save().unwrap().unwrap();
+        set_cookie
+            .strip_prefix("sid=")
+            .and_then(|t| t.split(';').next())
+            .unwrap()
+            .to_string()
+    }
+
+    fn get(sessions: &Arc<Sessions>, cookie: &str, key: &str) -> Option<Value> {
+        let header = format!("sid={}", cookie);
+        Session::new(sessions.clone(), Some(&header))
+            .get(key)
+            .cloned()
+    }
+
+    /// Flips one character of the part of `cookie` after `from`.
+    fn tamper(cookie: &str, from: usize) -> String {
+        let mut bytes = cookie.as_bytes().to_vec();
+        bytes[from] = if bytes[from] == b'A' { b'B' } else { b'A' };
+        String::from_utf8(bytes).unwrap()
+    }
+
+    fn session_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("netpup-sessions-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn signed_cookie_round_trip() {
+        let sessions = sessions(&[SECRET], Store::Cookie { encrypt: false });
+        let cookie = save(&sessions, None, "user", json!("alice"));
+        assert!(cookie.starts_with("s."));
+        assert_eq!(get(&sessions, &cookie, "user"), Some(json!("alice")));
+
+        let cookie = save(&sessions, Some(&cookie), "n", json!(1));
+        assert_eq!(get(&sessions, &cookie, "user"), Some(json!("alice")));
+        assert_eq!(get(&sessions, &cookie, "n"), Some(json!(1)));
+    }
+
+    #[test]
+    fn signed_cookie_rejects_forgeries() {
+        let sessions = sessions(&[SECRET], Store::Cookie { encrypt: false });
+        let cookie = save(&sessions, None, "user", json!("alice"));
+
+        // Changed data or tag
+        assert_eq!(get(&sessions, &tamper(&cookie, 4), "user"), None);
+        assert_eq!(
+            get(&sessions, &tamper(&cookie, cookie.len() - 2), "user"),
+            None
+        );
+        // Data signed with another secret
+        let (mode_body, _) = cookie.rsplit_once('.').unwrap();
+        let other = Sessions::new(
+            &["another secret of at least 32 bytes!".to_string()],
+            Store::Cookie { encrypt: false },
+            "sid".to_string(),
+            3600,
+        );
+        let forged = format!("{}.{}", mode_body, other.sign(mode_body.as_bytes()));
+        assert_eq!(get(&sessions, &forged, "user"), None);
+        // Unsigned, unknown mode or garbage
+        assert_eq!(get(&sessions, mode_body, "user"), None);
+        assert_eq!(get(&sessions, &format!("x{}", &cookie[1..]), "user"), None);
+        assert_eq!(get(&sessions, "garbage", "user"), None);
+        assert_eq!(get(&sessions, "", "user"), None);
+    }
+
+    #[test]
+    fn encrypted_cookie_round_trip() {
+        let sessions = sessions(&[SECRET], Store::Cookie { encrypt: true });
+        let cookie = save(&sessions, None, "user", json!("alice"));
+        assert!(cookie.starts_with("e."));
+        let sealed = BASE64_URL_SAFE_NO_PAD.decode(&cookie[2..]).unwrap();
+        assert!(!String::from_utf8_lossy(&sealed).contains("alice"));
+        assert_eq!(get(&sessions, &cookie, "user"), Some(json!("alice")));
+        // Fresh nonces make every cookie differ
+        assert_ne!(cookie, save(&sessions, None, "user", json!("alice")));
+    }
+
+    #[test]
+    fn encrypted_cookie_rejects_forgeries() {
+        let sessions = sessions(&[SECRET], Store::Cookie { encrypt: true });
+        let cookie = save(&sessions, None, "user", json!("alice"));
+        for at in [2, 10, 40, cookie.len() - 1] {
+            assert_eq!(get(&sessions, &tamper(&cookie, at), "user"), None);
+        }
+        // The mode is authenticated as well
+        assert_eq!(get(&sessions, &format!("s{}", &cookie[1..]), "user"), None);
+        assert_eq!(get(&sessions, "e.", "user"), None);
+        assert_eq!(get(&sessions, "e.AAAA", "user"), None);
+
+        let other = cookie_sessions(&["another secret of at least 32 bytes!"], true);
+        assert_eq!(get(&other, &cookie, "user"), None);
+    }
+
+    #[test]
+    fn old_secrets_verify_but_do_not_sign() {
+        for encrypt in [false, true] {
+            let before = cookie_sessions(&[OLD_SECRET], encrypt);
+            let cookie = save(&before, None, "user", json!("alice"));
+
+            let rotated = cookie_sessions(&[SECRET, OLD_SECRET], encrypt);
+            assert_eq!(get(&rotated, &cookie, "user"), Some(json!("alice")));
+            let resigned = save(&rotated, Some(&cookie), "n", json!(1));
+
+            let after = cookie_sessions(&[SECRET], encrypt);
+            assert_eq!(get(&after, &cookie, "user"), None);
+            assert_eq!(get(&after, &resigned, "user"), Some(json!("alice")));
+        }
+    }
+
+    #[test]
+    fn expired_sessions_are_ignored() {
+        let signed = cookie_sessions(&[SECRET], false);
+        let cookie = signed.store(None, &record(now() - 1, "old")).unwrap();
+        assert_eq!(get(&signed, &cookie, "old"), None);
+        let cookie = signed.store(None, &record(now() + 60, "new")).unwrap();
+        assert_eq!(get(&signed, &cookie, "new"), Some(json!(true)));
+
+        let memory = sessions(&[SECRET], Store::memory());
+        let cookie = memory.store(None, &record(now() - 1, "old")).unwrap();
+        assert_eq!(get(&memory, &cookie, "old"), None);
+    }
+
+    #[test]
+    fn memory_store_ids_are_signed() {
+        let sessions = sessions(&[SECRET], Store::memory());
+        let cookie = save(&sessions, None, "user", json!("alice"));
+        let (id, _) = cookie.split_once('.').unwrap();
+        assert_eq!(id.len(), 64);
+        assert_eq!(get(&sessions, &cookie, "user"), Some(json!("alice")));
+        assert_eq!(get(&sessions, id, "user"), None);
+        assert_eq!(get(&sessions, &tamper(&cookie, 70), "user"), None);
+        assert_eq!(
+            get(
+                &sessions,
+                &format!("{}.{}", "0".repeat(64), &cookie[65..]),
+                "user"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn destroy_and_rotate() {
+        let sessions = sessions(&[SECRET], Store::memory());
+        let cookie = save(&sessions, None, "user", json!("alice"));
+
+        let header = format!("sid={}", cookie);
+        let mut session = Session::new(sessions.clone(), Some(&header));
+        session.rotate();
+        let rotated = session.save().unwrap().unwrap();
+        assert!(!rotated.contains(&cookie));
+        assert_eq!(get(&sessions, &cookie, "user"), None);
+
+        let rotated = rotated["sid=".len()..].split(';').next().unwrap();
+        assert_eq!(get(&sessions, rotated, "user"), Some(json!("alice")));
+        let header = format!("sid={}", rotated);
+        let mut session = Session::new(sessions.clone(), Some(&header));
+        session.destroy();
+        let removal = session.save().unwrap().unwrap();
+        assert!(removal.starts_with("sid=; Path=/; Max-Age=0"));
+        assert_eq!(get(&sessions, rotated, "user"), None);
+
+        // Unchanged sessions send no cookie
+        let session = Session::new(sessions.clone(), None);
+        assert_eq!(session.save().unwrap(), None);
+    }
+
+    #[test]
+    fn set_after_destroy_starts_a_new_session() {
+        let sessions = sessions(&[SECRET], Store::memory());
+        let cookie = save(&sessions, None, "user", json!("alice"));
+
+        let header = format!("sid={}", cookie);
+        let mut session = Session::new(sessions.clone(), Some(&header));
+        session.destroy();
+        session.set("theme", json!("dark"));
+        let set_cookie = session.save().unwrap().unwrap();
+        let renewed = set_cookie["sid=".len()..].split(';').next().unwrap();
+        let (old_id, _) = cookie.split_once('.').unwrap();
+        assert!(!renewed.starts_with(old_id));
+        assert_eq!(get(&sessions, &cookie, "theme"), None);
+        assert_eq!(get(&sessions, renewed, "theme"), Some(json!("dark")));
+        assert_eq!(get(&sessions, renewed, "user"), None);
+    }
+
+    #[test]
+    fn oversized_cookies_fail() {
+        let sessions = sessions(&[SECRET], Store::Cookie { encrypt: false });
+        let mut session = Session::new(sessions, None);
+        session.set("big", json!("x".repeat(MAX_COOKIE_SIZE)));
+        assert!(session.save().is_err());
+    }
+
+    #[test]
+    fn memory_store_is_capped() {
+        let mut sessions = Sessions::new(
+            &[SECRET.to_string()],
+            Store::memory(),
+            "sid".to_string(),
+            3600,
+        );
+        sessions.max_sessions = 2;
+        let first = sessions.store(None, &record(now() + 10, "a")).unwrap();
+        let second = sessions.store(None, &record(now() + 30, "b")).unwrap();
+        let third = sessions.store(None, &record(now() + 20, "c")).unwrap();
+        let sessions = Arc::new(sessions);
+        // The session expiring first made room
+        assert_eq!(get(&sessions, &first, "a"), None);
+        assert_eq!(get(&sessions, &second, "b"), Some(json!(true)));
+        assert_eq!(get(&sessions, &third, "c"), Some(json!(true)));
+
+        // Updating a session at the cap evicts nothing
+        let (id, _) = third.split_once('.').unwrap();
+        sessions.store(Some(id), &record(now() + 40, "c")).unwrap();
+        assert_eq!(get(&sessions, &second, "b"), Some(json!(true)));
+        match &sessions.store {
+            Store::Memory(records) => assert_eq!(records.lock().unwrap().len(), 2),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn file_store_round_trip() {
+        let dir = session_dir("round-trip");
+        let sessions = sessions(&[SECRET], Store::File(dir.clone()));
+        let cookie = save(&sessions, None, "user", json!("alice"));
+        assert_eq!(session_files(&dir).len(), 1);
+        assert_eq!(get(&sessions, &cookie, "user"), Some(json!("alice")));
+        assert_eq!(get(&sessions, &tamper(&cookie, 70), "user"), None);
+
+        // Expired files are removed once they are presented
+        let expired = sessions.store(None, &record(now() - 1, "old")).unwrap();
+        assert_eq!(session_files(&dir).len(), 2);
+        assert_eq!(get(&sessions, &expired, "old"), None);
+        assert_eq!(session_files(&dir).len(), 1);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn file_store_sweeps_expired_files() {
+        let dir = session_dir("sweep");
+        let id = "ab".repeat(32);
+        fs::write(
+            dir.join(format!("{}.json", id)),
+            record(now() - 1, "old").to_json(),
+        )
+        .unwrap();
+        let broken = "cd".repeat(32);
+        fs::write(dir.join(format!("{}.json", broken)), b"{").unwrap();
+        // Files which are not sessions are neither removed nor counted
+        let others = [
+            "broken.json".to_string(),
+            format!("{}.json", "AB".repeat(32)),
+            format!("{}.json.bak", id),
+            "other.txt".to_string(),
+        ];
+        for name in &others {
+            fs::write(dir.join(name), b"{").unwrap();
+        }
+
+        // The first save sweeps
+        let sessions = sessions(&[SECRET], Store::File(dir.clone()));
+        save(&sessions, None, "user", json!("alice"));
+        assert_eq!(session_files(&dir).len(), 1);
+        assert!(!dir.join(format!("{}.json", broken)).exists());
+        for name in &others {
+            assert!(dir.join(name).exists(), "{}", name);
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn session_file_names() {
+        let id = "0f".repeat(32);
+        assert!(is_session_file(Path::new(&format!("/s/{}.json", id))));
+        assert!(!is_session_file(Path::new(&format!(
+            "/s/{}.json",
+            "0F".repeat(32)
+        ))));
+        assert!(!is_session_file(Path::new(&format!(
+            "/s/{}.json",
+            &id[1..]
+        ))));
+        assert!(!is_session_file(Path::new(&format!("/s/{}.tmp", id))));
+        assert!(!is_session_file(Path::new("/s/.json")));
+    }
+
+    #[test]
+    fn file_store_is_capped() {
+        let dir = session_dir("cap");
+        let mut sessions = Sessions::new(
+            &[SECRET.to_string()],
+            Store::File(dir.clone()),
+            "sid".to_string(),
+            3600,
+        );
+        sessions.max_sessions = 2;
+        fs::write(dir.join("notes.json"), b"{}").unwrap();
+        fs::write(dir.join("notes.json"), b"{}").unwrap();
+        let first = sessions.store(None, &record(now() + 10, "a")).unwrap();
+        let second = sessions.store(None, &record(now() + 30, "b")).unwrap();
+        let third = sessions.store(None, &record(now() + 20, "c")).unwrap();
+        let sessions = Arc::new(sessions);
+        assert_eq!(session_files(&dir).len(), 2);
+        // Other files neither count towards the cap nor are left behind by saves
+        let names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|t| t.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names.len(), 3);
+        assert!(names.iter().any(|t| t == "notes.json"));
+        assert_eq!(get(&sessions, &first, "a"), None);
+        assert_eq!(get(&sessions, &second, "b"), Some(json!(true)));
+        assert_eq!(get(&sessions, &third, "c"), Some(json!(true)));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
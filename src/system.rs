@@ -1,14 +1,29 @@
+use crate::access::{parse_cidrs, resolve_client_ip, AccessList, Cidr};
+use crate::auth::{BasicAuth, JwtAuth};
+use crate::connections::ConnectionLimiter;
+use crate::cookies::{self, SameSite};
+use crate::cors::Cors;
 use crate::errors::{DogError, DogResult, HttpCode, NetError, NetResult};
+use crate::forms::{FormConfig, FormLimits};
+use crate::headers::Headers;
 use crate::logger::Logger;
-use crate::request::{Headers, HttpRequest, Methods};
-use crate::response::{ContentType, HttpResponse};
-use crate::script::ScriptLoader;
+use crate::request::{HttpRequest, Methods, RequestLimits};
+use crate::mime::MimeRegistry;
+use crate::ratelimit::RateLimiter;
+use crate::response::HttpResponse;
+use crate::sandbox::FileSandbox;
+use crate::script::{ScriptLimits, ScriptLoader};
+use crate::security::{add_nonce, SecurityHeaders};
+use crate::sessions::{Sessions, Store};
+use crate::{NAME, VERSION};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs;
 use std::str::FromStr;
-use toml::Table;
+use std::sync::Arc;
+use std::time::Duration;
+use toml::{Table, Value};
 
 fn unwrap_or_error<T>(results: Vec<Option<T>>) -> Option<Vec<T>> {
     let mut unwrapped = Vec::new();
@@ -24,6 +39,9 @@ fn unwrap_or_error<T>(results: Vec<Option<T>>) -> Option<Vec<T>> {
 }
 
 fn url_resolve(route: &Route, url: &str, method: &Methods) -> Result<Route, ()> {
+    if !route.methods.contains(method) {
+        return Err(());
+    }
     let resolved_path = if route.url.contains("*") {
         let parts: Vec<&str> = route.url.split('*').collect();
         if parts.len() > 2 {
@@ -33,9 +51,6 @@ fn url_resolve(route: &Route, url: &str, method: &Methods) -> Result<Route, ()>
             return Err(());
         }
         let dynamic_part = &url[parts[0].len()..url.len() - parts.get(1).unwrap_or(&"").len()];
-        if !route.methods.contains(method) {
-            return Err(());
-        }
         route.path.replace('*', dynamic_part)
     } else {
         if route.url != url {
@@ -45,11 +60,7 @@ fn url_resolve(route: &Route, url: &str, method: &Methods) -> Result<Route, ()>
     };
     Ok(Route {
         path: resolved_path,
-        url: route.url.clone(),
-        methods: route.methods.clone(),
-        name: (&route).name.clone().into(),
-        path_is_script: route.path_is_script,
-        content_type: Some(route.content_type.clone().unwrap().to_string()),
+        ..route.clone()
     })
 }
 
@@ -61,7 +72,7 @@ fn url_resolve_mult(routes: &[Route], url: &str, method: Methods) -> NetResult<R
         }
     }
     Err(NetError::new(
-        HttpCode::NotFound,
+        HttpCode::NOT_FOUND,
         Some("No matching route found".to_string()),
     ))
 }
@@ -72,9 +83,177 @@ struct ConfigToml {
     pub cwd: Option<String>,
     pub port: Option<u16>,
     pub max_cons: Option<u32>,
+    pub queue_depth: Option<usize>,
+    pub shutdown_grace: Option<u64>,
+    pub reload_scripts: Option<bool>,
+    pub lua_path: Option<Vec<String>>,
+    pub sandbox: Option<SandboxCfg>,
+    pub upload_dir: Option<String>,
+    pub sessions: Option<SessionsCfg>,
     pub logger: Option<LoggerCfg>,
     pub routes: Table,
+    pub defaults: Option<Table>,
     pub errors: Option<Table>,
+    pub mime: Option<MimeCfg>,
+    pub server_header: Option<ServerHeaderCfg>,
+    pub access: Option<Value>,
+    pub trusted_proxies: Option<Vec<String>>,
+    pub rate_limit: Option<Table>,
+    pub security_headers: Option<Value>,
+    pub limits: Option<LimitsCfg>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+}
+
+/// `server_header = false` hides the `Server` header, a string replaces its value.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ServerHeaderCfg {
+    Enabled(bool),
+    Custom(String),
+}
+
+#[derive(Deserialize)]
+struct MimeCfg {
+    sniff: Option<bool>,
+    types: Option<HashMap<String, String>>,
+}
+
+/// Directories scripts may access, without `[sandbox]` the working directory is read-write.
+#[derive(Deserialize)]
+struct SandboxCfg {
+    read_only: Option<Vec<String>>,
+    read_write: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct SessionsCfg {
+    secret: String,
+    old_secrets: Option<Vec<String>>,
+    store: Option<String>,
+    encrypt: Option<bool>,
+    dir: Option<String>,
+    cookie: Option<String>,
+    max_age: Option<i64>,
+    max_sessions: Option<usize>,
+    secure: Option<bool>,
+    http_only: Option<bool>,
+    same_site: Option<String>,
+}
+
+impl SessionsCfg {
+    fn load(self, logger: &Logger) -> DogResult<Sessions> {
+        let ill_formatted = |key: &str| {
+            DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                format!("Ill formatted key 'sessions.{}'", key),
+            )
+        };
+        let mut secrets = vec![self.secret];
+        secrets.extend(self.old_secrets.unwrap_or_default());
+        if secrets.iter().any(|t| t.len() < 32) {
+            return Err(DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                "Session secrets must be at least 32 bytes long".to_string(),
+            ));
+        }
+        let encrypt = self.encrypt.unwrap_or(false);
+        let store = match self.store.as_deref().unwrap_or("cookie") {
+            "cookie" => Store::Cookie { encrypt },
+            _ if encrypt => return Err(ill_formatted("encrypt")),
+            "memory" => Store::memory(),
+            "file" => {
+                let dir = self
+                    .dir
+                    .and_then(|t| fs::canonicalize(t).ok())
+                    .filter(|t| t.is_dir())
+                    .ok_or_else(|| ill_formatted("dir"))?;
+                Store::File(dir)
+            }
+            _ => return Err(ill_formatted("store")),
+        };
+        let cookie_name = self.cookie.unwrap_or_else(|| "netpup_session".to_string());
+        if !cookies::is_valid_name(&cookie_name) {
+            return Err(ill_formatted("cookie"));
+        }
+        let max_age = self.max_age.unwrap_or(86400);
+        if max_age <= 0 {
+            return Err(ill_formatted("max_age"));
+        }
+
+        let mut sessions = Sessions::new(&secrets, store, cookie_name, max_age);
+        match self.max_sessions {
+            Some(0) => return Err(ill_formatted("max_sessions")),
+            Some(max_sessions) => sessions.max_sessions = max_sessions,
+            None => {}
+        }
+        sessions.secure = self.secure.unwrap_or(false);
+        sessions.http_only = self.http_only.unwrap_or(true);
+        sessions.same_site = match self.same_site {
+            Some(same_site) => {
+                Some(SameSite::from_str(&same_site).ok_or_else(|| ill_formatted("same_site"))?)
+            }
+            None => Some(SameSite::Lax),
+        };
+        if sessions.same_site == Some(SameSite::None) && !sessions.secure {
+            return Err(ill_formatted("same_site"));
+        }
+        Ok(sessions)
+    }
+}
+
+#[derive(Deserialize)]
+struct LimitsCfg {
+    request_line: Option<usize>,
+    header_bytes: Option<usize>,
+    headers: Option<usize>,
+    body: Option<usize>,
+    header_timeout: Option<usize>,
+    body_timeout: Option<usize>,
+    form_field: Option<usize>,
+    form_file: Option<usize>,
+    form_parts: Option<usize>,
+    form_total: Option<usize>,
+    form_memory: Option<usize>,
+}
+
+impl LimitsCfg {
+    fn load(self, logger: &Logger) -> DogResult<RequestLimits> {
+        let defaults = RequestLimits::default();
+        let positive = |key: &str, value: Option<usize>, default: usize| match value {
+            Some(0) => Err(DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                format!("Ill formatted key 'limits.{}'", key),
+            )),
+            Some(value) => Ok(value),
+            None => Ok(default),
+        };
+        let seconds = |key: &str, value: Option<usize>, default: Duration| {
+            positive(key, value, default.as_secs() as usize).map(|t| Duration::from_secs(t as u64))
+        };
+        Ok(RequestLimits {
+            request_line: positive("request_line", self.request_line, defaults.request_line)?,
+            header_bytes: positive("header_bytes", self.header_bytes, defaults.header_bytes)?,
+            headers: positive("headers", self.headers, defaults.headers)?,
+            body: positive("body", self.body, defaults.body)?,
+            header_timeout: seconds(
+                "header_timeout",
+                self.header_timeout,
+                defaults.header_timeout,
+            )?,
+            body_timeout: seconds("body_timeout", self.body_timeout, defaults.body_timeout)?,
+            form: FormLimits {
+                field: positive("form_field", self.form_field, defaults.form.field)?,
+                file: positive("form_file", self.form_file, defaults.form.file)?,
+                parts: positive("form_parts", self.form_parts, defaults.form.parts)?,
+                total: positive("form_total", self.form_total, defaults.form.total)?,
+                memory: positive("form_memory", self.form_memory, defaults.form.memory)?,
+            },
+        })
+    }
 }
 
 #[derive(Deserialize)]
@@ -99,7 +278,69 @@ pub struct Route {
     url: String,
     methods: Vec<Methods>,
     path_is_script: bool,
-    content_type: Option<String>
+    content_type: Option<String>,
+    headers: Headers,
+    access: Option<AccessList>,
+    auth: Option<BasicAuth>,
+    jwt: Option<JwtAuth>,
+    rate_limit: Option<RateLimiter>,
+    cors: Option<Cors>,
+    script_limits: ScriptLimits,
+}
+
+/// Merges the `[defaults]` table into a route table.
+/// Keys set on the route win, sub-tables (like `headers`) are merged key by key.
+fn merge_defaults(route: &mut Table, defaults: &Table) {
+    for (key, value) in defaults {
+        match (route.get_mut(key), value) {
+            (None, _) => {
+                route.insert(key.clone(), value.clone());
+            }
+            (Some(Value::Table(route_sub)), Value::Table(defaults_sub)) => {
+                merge_defaults(route_sub, defaults_sub)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Builds a `Cache-Control` value from a route's `cache` table.
+fn cache_control(logger: &Logger, t: &Table) -> DogResult<String> {
+    let flag = |key: &str| -> DogResult<bool> {
+        match t.get(key) {
+            None => Ok(false),
+            Some(Value::Boolean(b)) => Ok(*b),
+            Some(_) => Err(DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                format!("Ill formatted key 'cache.{}'", key),
+            )),
+        }
+    };
+    if flag("no_store")? {
+        return Ok("no-store".to_string());
+    }
+    let mut directives = vec![if flag("private")? { "private" } else { "public" }.to_string()];
+    if flag("no_cache")? {
+        directives.push("no-cache".to_string());
+    }
+    match t.get("max_age") {
+        None => {}
+        Some(Value::Integer(max_age)) if *max_age >= 0 => {
+            directives.push(format!("max-age={}", max_age))
+        }
+        Some(_) => {
+            return Err(DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                "Ill formatted key 'cache.max_age'".to_string(),
+            ))
+        }
+    }
+    if flag("immutable")? {
+        directives.push("immutable".to_string());
+    }
+    Ok(directives.join(", "))
 }
 
 impl Route {
@@ -164,12 +405,109 @@ impl Route {
             ));
         };
         
-        let content_type = if t.contains_key("content_type") {
-            t.get("content_type").unwrap().as_str().unwrap().to_string()
-        } else {
-            "".to_string()
+        let content_type = t
+            .get("content_type")
+            .map(|t1| t1.as_str().unwrap().to_string());
+
+        let mut headers = Headers::new();
+        if let Some(headers_t) = t.get("headers") {
+            let ill_formatted = || {
+                DogError::new(
+                    logger,
+                    "usr-cfgensure-cfgld".to_string(),
+                    "Ill formatted key 'headers'".to_string(),
+                )
+            };
+            for (name, value) in headers_t.as_table().ok_or_else(ill_formatted)? {
+                // A list sends the header several times
+                let values = match value {
+                    Value::Array(values) => values.iter().map(|t1| t1.as_str()).collect(),
+                    value => vec![value.as_str()],
+                };
+                for value in values {
+                    headers
+                        .append(name, value.ok_or_else(ill_formatted)?)
+                        .map_err(|_e| ill_formatted())?;
+                }
+            }
+        }
+        if let Some(cache_t) = t.get("cache") {
+            let cache_t = cache_t.as_table().ok_or_else(|| {
+                DogError::new(
+                    logger,
+                    "usr-cfgensure-cfgld".to_string(),
+                    "Ill formatted key 'cache'".to_string(),
+                )
+            })?;
+            headers
+                .insert("Cache-Control", &cache_control(logger, cache_t)?)
+                .expect("Cache-Control is always a valid header");
+        }
+
+        let access = match t.get("access") {
+            Some(access_v) => Some(AccessList::new(logger, access_v)?),
+            None => None,
+        };
+        let auth = match t.get("auth") {
+            Some(Value::Table(auth_t)) => Some(BasicAuth::new(logger, auth_t)?),
+            Some(_) => {
+                return Err(DogError::new(
+                    logger,
+                    "usr-cfgensure-cfgld".to_string(),
+                    "Ill formatted key 'auth'".to_string(),
+                ))
+            }
+            None => None,
+        };
+        let jwt = match t.get("jwt") {
+            Some(Value::Table(jwt_t)) => Some(JwtAuth::new(logger, jwt_t)?),
+            Some(_) => {
+                return Err(DogError::new(
+                    logger,
+                    "usr-cfgensure-cfgld".to_string(),
+                    "Ill formatted key 'jwt'".to_string(),
+                ))
+            }
+            None => None,
+        };
+
+        let rate_limit = match t.get("rate_limit") {
+            Some(Value::Table(rate_limit_t)) => Some(RateLimiter::new(logger, rate_limit_t)?),
+            Some(_) => {
+                return Err(DogError::new(
+                    logger,
+                    "usr-cfgensure-cfgld".to_string(),
+                    "Ill formatted key 'rate_limit'".to_string(),
+                ))
+            }
+            None => None,
+        };
+
+        let cors = match t.get("cors") {
+            Some(Value::Table(cors_t)) => Some(Cors::new(logger, cors_t)?),
+            Some(_) => {
+                return Err(DogError::new(
+                    logger,
+                    "usr-cfgensure-cfgld".to_string(),
+                    "Ill formatted key 'cors'".to_string(),
+                ))
+            }
+            None => None,
+        };
+
+        let get_limit = |key: &str| match t.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(value)) if *value > 0 => Ok(Some(*value as usize)),
+            Some(_) => Err(DogError::new(
+                logger,
+                "usr-cfgensure-cfgld".to_string(),
+                format!("Ill formatted key '{}'", key),
+            )),
+        };
+        let script_limits = ScriptLimits {
+            timeout: get_limit("timeout_ms")?.map(|t1| Duration::from_millis(t1 as u64)),
+            memory: get_limit("memory_limit")?,
         };
-            
 
         Ok(Self {
             name,
@@ -177,22 +515,28 @@ impl Route {
             url: t.get("url").unwrap().as_str().unwrap().to_string(),
             methods: methods?,
             path_is_script,
-            content_type: Some(content_type),
+            content_type,
+            headers,
+            access,
+            auth,
+            jwt,
+            rate_limit,
+            cors,
+            script_limits,
         })
     }
 
     pub fn tbljob(
         logger: Logger,
         t: Table,
+        defaults: &Table,
     ) -> DogResult<(HashMap<String, Route>, HashMap<String, String>)> {
         let mut hm_r = HashMap::new();
         let mut hm_s = HashMap::new();
         for x in t.keys() {
-            let rt = Route::new(
-                &logger,
-                x.to_string(),
-                t.get(x).unwrap().as_table().unwrap().to_owned(),
-            )?;
+            let mut route_t = t.get(x).unwrap().as_table().unwrap().to_owned();
+            merge_defaults(&mut route_t, defaults);
+            let rt = Route::new(&logger, x.to_string(), route_t)?;
             hm_r.insert(x.to_string(), rt.to_owned());
             if rt.path_is_script {
                 hm_s.insert(rt.name, rt.path);
@@ -220,7 +564,7 @@ impl ErrorRoute {
 
         Ok(Self {
             _erc: erc,
-            path: t.get("path").unwrap().to_string(),
+            path: t.get("path").unwrap().as_str().unwrap().to_string(),
         })
     }
 
@@ -246,10 +590,21 @@ pub struct System {
     pub ip: String,
     pub port: u16,
     pub max_cons: u32,
+    pub queue_depth: usize,
+    pub shutdown_grace: Duration,
+    pub reload_scripts: bool,
     pub routes: HashMap<String, Route>,
     pub errors: HashMap<u16, ErrorRoute>,
     pub logger: Logger,
     pub script_loader: ScriptLoader,
+    pub mime: Arc<MimeRegistry>,
+    pub server_header: Option<String>,
+    pub access: AccessList,
+    pub trusted_proxies: Arc<Vec<Cidr>>,
+    pub rate_limit: Option<RateLimiter>,
+    pub security_headers: SecurityHeaders,
+    pub limits: RequestLimits,
+    pub connections: ConnectionLimiter,
 }
 
 impl System {
@@ -273,15 +628,121 @@ impl System {
         } else {
             HashMap::new()
         };
-        let (routes, scripts) = Route::tbljob(logger.clone(), cfg_t.routes)?;
+        let mime_cfg = cfg_t.mime.unwrap_or(MimeCfg { sniff: None, types: None });
+        let mime_types = mime_cfg.types.unwrap_or_default();
+        for (ext, mime) in &mime_types {
+            if Headers::validate("Content-Type", mime).is_err() {
+                return Err(DogError::new(
+                    &logger,
+                    "usr-cfgensure-cfgld".to_string(),
+                    format!("Ill formatted MIME type for '{}'", ext),
+                ));
+            }
+        }
+        let mime = Arc::new(MimeRegistry::new(mime_types, mime_cfg.sniff.unwrap_or(false)));
+        let server_header = match cfg_t.server_header {
+            Some(ServerHeaderCfg::Enabled(false)) => None,
+            Some(ServerHeaderCfg::Custom(server)) => {
+                if Headers::validate("Server", &server).is_err() {
+                    return Err(DogError::new(
+                        &logger,
+                        "usr-cfgensure-cfgld".to_string(),
+                        "Ill formatted key 'server_header'".to_string(),
+                    ));
+                }
+                Some(server)
+            }
+            _ => Some(format!("{}/{}", NAME, VERSION)),
+        };
+        let defaults = cfg_t.defaults.unwrap_or_default();
+        let (routes, scripts) = Route::tbljob(logger.clone(), cfg_t.routes, &defaults)?;
+        let access = match cfg_t.access {
+            Some(access_v) => AccessList::new(&logger, &access_v)?,
+            None => AccessList::default(),
+        };
+        let trusted_proxies = parse_cidrs(
+            &logger,
+            "trusted_proxies",
+            &cfg_t.trusted_proxies.unwrap_or_default(),
+        )?;
+        let rate_limit = match cfg_t.rate_limit {
+            Some(rate_limit_t) => Some(RateLimiter::new(&logger, &rate_limit_t)?),
+            None => None,
+        };
+        let security_headers = match cfg_t.security_headers {
+            Some(security_v) => SecurityHeaders::new(&logger, &security_v)?,
+            None => SecurityHeaders::default(),
+        };
+        let limits = match cfg_t.limits {
+            Some(limits_cfg) => limits_cfg.load(&logger)?,
+            None => RequestLimits::default(),
+        };
+        let (read_only, read_write) = match cfg_t.sandbox {
+            Some(sandbox_cfg) => (
+                sandbox_cfg.read_only.unwrap_or_default(),
+                sandbox_cfg.read_write.unwrap_or_default(),
+            ),
+            None => (vec![], vec![".".to_string()]),
+        };
+        let sandbox = FileSandbox::new(&logger, &read_only, &read_write)?;
+        let upload_dir = match &cfg_t.upload_dir {
+            Some(upload_dir) => Some(fs::canonicalize(upload_dir).map_err(|_| {
+                DogError::new(
+                    &logger,
+                    "usr-cfgensure-cfgld".to_string(),
+                    "Ill formatted key 'upload_dir'".to_string(),
+                )
+            })?),
+            None => None,
+        };
+        let sessions = match cfg_t.sessions {
+            Some(sessions_cfg) => Some(sessions_cfg.load(&logger)?),
+            None => None,
+        };
+        if cfg_t.max_connections == Some(0)
+            || cfg_t.max_connections_per_ip == Some(0)
+            || cfg_t.queue_depth == Some(0)
+        {
+            return Err(DogError::new(
+                &logger,
+                "usr-cfgensure-cfgld".to_string(),
+                "Connection and queue limits must be greater than 0".to_string(),
+            ));
+        }
+        let connections = ConnectionLimiter::new(
+            cfg_t.max_connections.unwrap_or(1024),
+            cfg_t.max_connections_per_ip,
+        );
         Ok(Self {
             ip: cfg_t.ip,
             port: cfg_t.port.unwrap_or_else(|| 8080),
             max_cons: cfg_t.max_cons.unwrap_or_else(|| 100),
+            queue_depth: cfg_t.queue_depth.unwrap_or(256),
+            shutdown_grace: Duration::from_secs(cfg_t.shutdown_grace.unwrap_or(10)),
+            reload_scripts: cfg_t.reload_scripts.unwrap_or(false),
             routes,
             errors,
-            script_loader: ScriptLoader::new(&logger, scripts)?,
-            logger
+            script_loader: ScriptLoader::new(
+                &logger,
+                scripts,
+                cfg_t.lua_path.unwrap_or_default(),
+                sandbox,
+                FormConfig {
+                    limits: limits.form,
+                    upload_dir,
+                },
+                sessions,
+                mime.clone(),
+            )?,
+            logger,
+            mime,
+            server_header,
+            access,
+            trusted_proxies: Arc::new(trusted_proxies),
+            rate_limit,
+            security_headers,
+            limits,
+            connections,
         })
     }
 
@@ -311,10 +772,16 @@ impl System {
     pub fn netpup_error(&mut self, error: DogError) -> HttpResponse {
         self.logger
             .error(format!("Serving client with NetPup error [{}]", error.__fmtx()).as_str());
+        // A script running out of time hints at an overloaded server rather than a broken one
+        let code = if error.name == "usr-script-timeout" {
+            HttpCode::SERVICE_UNAVAILABLE
+        } else {
+            HttpCode::INTERNAL_ERROR
+        };
         HttpResponse::new(
-            (HttpCode::InternalError, error.name),
+            (code, error.name),
             Headers::new(),
-            (vec![], ContentType::NONE.to_string()),
+            (vec![], "".to_string()),
             false,
         )
     }
@@ -333,24 +800,26 @@ impl System {
     }
 
     pub fn route_error(&mut self, error: NetError) -> HttpResponse {
-        let erc = (&error.erc).to_owned() as u16;
+        let erc = error.erc.to_num();
         if (&self.errors).contains_key(&erc) {
             let r_fn = &self.errors.get(&erc).unwrap().path;
             let content = self.load_content_path(r_fn.into());
             if content.is_err() {
                 return self.netpup_error(content.unwrap_err());
             }
+            let content = content.unwrap();
+            let content_type = self.mime.lookup_file(r_fn, &content);
             HttpResponse::new(
                 (error.erc, error.details),
-                Headers::new(),
-                (content.unwrap(), ContentType::from_file_name(&*r_fn).to_string()),
+                error.headers,
+                (content, content_type),
                 false,
             )
         } else {
             HttpResponse::new(
                 (error.erc, error.details),
-                Headers::new(),
-                (format!("Error {}", erc).into_bytes(), ContentType::HTML.to_string()),
+                error.headers,
+                (format!("Error {}", erc).into_bytes(), self.mime.resolve("html")),
                 false,
             )
         }
@@ -362,17 +831,81 @@ impl System {
             return self.netpup_error(content.unwrap_err());
         }
 
-        let content_type = route.content_type.or(Some(ContentType::from_file_name(&*route.path).to_string())).unwrap();
-        
+        let content = content.unwrap();
+        let content_type = route
+            .content_type
+            .map(|t| self.mime.resolve(&t))
+            .unwrap_or_else(|| self.mime.lookup_file(&route.path, &content));
+
         HttpResponse::new(
-            (HttpCode::OK, "OK".to_string()),
-            Headers::new(),
-            (content.unwrap(), content_type),
+            (HttpCode::OK, HttpCode::OK.canonical_reason().to_string()),
+            route.headers,
+            (content, content_type),
             false,
         )
     }
 
-    pub fn route(&mut self, req: HttpRequest) -> HttpResponse {
+    /// Applies the headers every response gets, right before it is sent.
+    pub fn finalize_response(&self, response: &mut HttpResponse) {
+        response.add_standard_headers(self.server_header.as_deref());
+        response.add_default_headers(&self.security_headers.headers(None));
+    }
+
+    fn forbidden(&mut self, req: &HttpRequest) -> HttpResponse {
+        self.logger
+            .info(format!("Denied access for '{}'", req.format()).as_str());
+        self.route_error(NetError::new(
+            HttpCode::FORBIDDEN,
+            Some("Access denied".to_string()),
+        ))
+    }
+
+    /// Takes a token from the global and the route's limiter if they are `keyed_on_user`, or not,
+    /// and returns the `RateLimit-*` headers of the most specific one, `limits` if none applies.
+    fn check_rate_limits(
+        &mut self,
+        req: &HttpRequest,
+        route: Option<&Route>,
+        keyed_on_user: bool,
+        mut limits: Headers,
+    ) -> NetResult<Headers> {
+        let limiters = [
+            self.rate_limit.as_ref(),
+            route.and_then(|t| t.rate_limit.as_ref()),
+        ];
+        for limiter in limiters
+            .into_iter()
+            .flatten()
+            .filter(|t| t.is_keyed_on_user() == keyed_on_user)
+        {
+            limits = limiter.check(req).inspect_err(|_e| {
+                self.logger
+                    .info(format!("Rate limited '{}'", req.format()).as_str());
+            })?;
+        }
+        Ok(limits)
+    }
+
+    pub fn route(&mut self, mut req: HttpRequest) -> HttpResponse {
+        let nonce = self.security_headers.new_nonce();
+        req.csp_nonce = nonce.clone();
+        let mut response = self.dispatch(req);
+        if nonce.is_some() {
+            response.add_default_headers(&self.security_headers.headers(nonce.as_deref()));
+        }
+        response
+    }
+
+    fn dispatch(&mut self, mut req: HttpRequest) -> HttpResponse {
+        if let Some(peer) = req.client_ip {
+            req.client_ip = Some(resolve_client_ip(peer, &req.headers, &self.trusted_proxies));
+        }
+        if req.client_ip.is_some_and(|ip| !self.access.is_allowed(ip)) {
+            return self.forbidden(&req);
+        }
+        if let Some(response) = self.preflight(&req) {
+            return response;
+        }
         let response = url_resolve_mult(
             &self
                 .routes
@@ -382,35 +915,234 @@ impl System {
             &*req.path,
             req.method.clone(),
         );
-        if response.is_err() {
+        match response {
+            Err(e) => {
+                self.logger.info(
+                    format!(
+                        "No route available for '{}', responding with error",
+                        req.format()
+                    )
+                    .as_str(),
+                );
+                if let Err(e) = self
+                    .check_rate_limits(&req, None, false, Headers::new())
+                    .and_then(|t| self.check_rate_limits(&req, None, true, t))
+                {
+                    return self.route_error(e);
+                }
+                self.route_error(e)
+            }
+            Ok(route) => {
+                let cors_headers = route
+                    .cors
+                    .as_ref()
+                    .map(|t| t.response_headers(&req.headers))
+                    .unwrap_or_default();
+                let mut response = self.serve(route, req);
+                response.add_default_headers(&cors_headers);
+                response
+            }
+        }
+    }
+
+    /// Answers CORS preflight requests (`OPTIONS` with `Access-Control-Request-Method`)
+    /// for routes with `cors` settings.
+    fn preflight(&mut self, req: &HttpRequest) -> Option<HttpResponse> {
+        if req.method != Methods::OPTIONS {
+            return None;
+        }
+        let method = Methods::from_str(req.headers.get("Access-Control-Request-Method")?).ok()?;
+        let routes = self.routes.values().cloned().collect::<Vec<Route>>();
+        let route = url_resolve_mult(&routes, &req.path, method).ok()?;
+        let cors = route.cors.as_ref()?;
+        if let (Some(access), Some(ip)) = (&route.access, req.client_ip) {
+            if !access.is_allowed(ip) {
+                return Some(self.forbidden(req));
+            }
+        }
+        // Preflights are cheap to send, so they use up tokens like any other request
+        let limits = match self
+            .check_rate_limits(req, Some(&route), false, Headers::new())
+            .and_then(|t| self.check_rate_limits(req, Some(&route), true, t))
+        {
+            Ok(limits) => limits,
+            Err(e) => return Some(self.route_error(e)),
+        };
+
+        let route_methods: Vec<String> = route.methods.iter().map(|t| format!("{:?}", t)).collect();
+        let mut headers = match cors.preflight_headers(&req.headers, &route_methods) {
+            Some(headers) => headers,
+            None => {
+                self.logger
+                    .info(format!("Rejected CORS preflight '{}'", req.format()).as_str());
+                Headers::new()
+            }
+        };
+        headers.extend(&limits);
+        Some(HttpResponse::new(
+            (HttpCode::NO_CONTENT, HttpCode::NO_CONTENT.canonical_reason().to_string()),
+            headers,
+            (vec![], "".to_string()),
+            false,
+        ))
+    }
+
+    /// Serves a request on its matching route.
+    fn serve(&mut self, route: Route, mut req: HttpRequest) -> HttpResponse {
+        if let (Some(access), Some(ip)) = (&route.access, req.client_ip) {
+            if !access.is_allowed(ip) {
+                return self.forbidden(&req);
+            }
+        }
+        let limits = match self.check_rate_limits(&req, Some(&route), false, Headers::new()) {
+            Ok(limits) => limits,
+            Err(e) => return self.route_error(e),
+        };
+        if let Some(auth) = &route.auth {
+            match auth.authenticate(&req.headers) {
+                Ok(user) => req.user = Some(user),
+                Err(e) => {
+                    self.logger.info(
+                        format!("Rejected '{}' ({})", req.format(), e.details).as_str(),
+                    );
+                    return self.route_error(e);
+                }
+            }
+        }
+        if let Some(jwt) = &route.jwt {
+            match jwt.authenticate(&req.headers) {
+                Ok(claims) => {
+                    if req.user.is_none() {
+                        req.user = claims
+                            .get("sub")
+                            .and_then(|t| t.as_str())
+                            .map(|t| t.to_string());
+                    }
+                    req.claims = Some(claims);
+                }
+                Err(e) => {
+                    self.logger.info(
+                        format!("Rejected '{}' ({})", req.format(), e.details).as_str(),
+                    );
+                    return self.route_error(e);
+                }
+            }
+        }
+        let limits = match self.check_rate_limits(&req, Some(&route), true, limits) {
+            Ok(limits) => limits,
+            Err(e) => return self.route_error(e),
+        };
+        let mut response = if route.path_is_script {
             self.logger.info(
-                format!(
-                    "No route available for '{}', responding with error",
-                    req.format()
-                )
-                .as_str(),
+                format!("Routing < {} > to script {}", req.format(), route.path).as_str(),
             );
-            self.route_error(response.unwrap_err())
-        } else {
-            let route = response.unwrap();
-            if route.path_is_script {
-                let ret = self.script_loader.run_script(&route.name, req);
-                return if ret.is_err() {
-                    self.logger
-                        .error(format!("Got an error from script {}", route.name).as_str());
-                    self.netpup_error(ret.unwrap_err())
-                } else {
-                    let response = ret.unwrap();
+            let ret = self
+                .script_loader
+                .run_script(&route.name, req, &route.script_limits);
+            match ret {
+                Ok(mut response) => {
                     if response.reroute {
                         self.route_error(response.to_net_error())
                     } else {
+                        response.add_default_headers(&route.headers);
                         response
                     }
-                };
+                }
+                Err(e) => {
+                    self.logger
+                        .error(format!("Got an error from script {}", route.name).as_str());
+                    self.netpup_error(e)
+                }
             }
+        } else {
             self.logger
                 .info(format!("Routing < {} > to {}", req.format(), route.path).as_str());
-            self.route_to_response(route)
-        }
+            let mut response = self.route_to_response(route);
+            // Only static files are trusted with the nonce, scripts add it to their own tags
+            if let Some(nonce) = &req.csp_nonce {
+                response.map_html(|t| add_nonce(t, nonce));
+            }
+            response
+        };
+        response.add_default_headers(&limits);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system(config: &str) -> System {
+        System::new(toml::from_str(config).unwrap()).unwrap()
+    }
+
+    fn request(lines: &[&str]) -> HttpRequest {
+        let mut req = HttpRequest::from_raw(lines.iter().map(|t| t.to_string()).collect()).unwrap();
+        req.client_ip = Some("10.0.0.1".parse().unwrap());
+        req
+    }
+
+    fn status(response: &HttpResponse) -> String {
+        let raw = response.make();
+        String::from_utf8_lossy(&raw)
+            .lines()
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn preflights_are_rate_limited() {
+        let mut system = system(
+            "ip = \"127.0.0.1\"\n\
+             [routes.api]\n\
+             url = \"/api\"\n\
+             methods = [\"GET\"]\n\
+             path = \"README.md\"\n\
+             cors = { origins = \"*\" }\n\
+             rate_limit = { requests = 1 }\n",
+        );
+        let preflight = [
+            "OPTIONS /api HTTP/1.1",
+            "Origin: https://a.test",
+            "Access-Control-Request-Method: GET",
+        ];
+        let response = system.route(request(&preflight));
+        assert!(status(&response).starts_with("HTTP/1.1 204"));
+        assert!(String::from_utf8_lossy(&response.make()).contains("RateLimit-Remaining: 0"));
+        assert!(status(&system.route(request(&preflight))).starts_with("HTTP/1.1 429"));
+        assert!(status(&system.route(request(&["GET /api HTTP/1.1"]))).starts_with("HTTP/1.1 429"));
+    }
+
+    #[test]
+    fn static_html_gets_the_nonce() {
+        let dir = std::env::temp_dir().join(format!("netpup-system-nonce-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("page.html");
+        fs::write(&page, "<p>hi</p><script>go()</script>").unwrap();
+        let mut system = system(&format!(
+            "ip = \"127.0.0.1\"\n\
+             security_headers = {{ csp = \"default-src 'self'\", csp_nonce = true }}\n\
+             [routes.page]\n\
+             url = \"/\"\n\
+             methods = [\"GET\"]\n\
+             path = {:?}\n",
+            page.to_string_lossy()
+        ));
+        let raw = system.route(request(&["GET / HTTP/1.1"])).make();
+        let raw = String::from_utf8(raw).unwrap();
+        let nonce = raw
+            .split("'nonce-")
+            .nth(1)
+            .unwrap()
+            .split('\'')
+            .next()
+            .unwrap();
+        let body = format!("<p>hi</p><script nonce=\"{}\">go()</script>", nonce);
+        assert!(raw.ends_with(&body));
+        let length = format!("Content-Length: {}", body.len());
+        assert!(raw.lines().any(|t| t.trim_end() == length));
+        let _ = fs::remove_dir_all(dir);
     }
 }
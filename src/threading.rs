@@ -1,62 +1,197 @@
 use crate::logger::Logger;
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+/// How long `Drop` waits for workers which were not shut down before.
+const DROP_GRACE: Duration = Duration::from_secs(5);
+
 pub struct ThreadPool {
-    _workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    workers: Vec<Worker>,
+    sender: Option<mpsc::SyncSender<QueuedJob>>,
+    queue_depth: usize,
+    context: WorkerContext,
+}
+
+/// State shared by all workers of a pool.
+#[derive(Clone)]
+struct WorkerContext {
+    logger: Logger,
+    receiver: Arc<Mutex<mpsc::Receiver<QueuedJob>>>,
+    queued: Arc<AtomicUsize>,
+    panics: Arc<AtomicUsize>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+struct QueuedJob {
+    job: Job,
+    queued_at: Instant,
+}
+
 impl ThreadPool {
-    pub fn new(logger: &Logger, size: usize) -> ThreadPool {
+    /// Spawns `size` workers, at most `queue_depth` jobs wait for a free worker.
+    pub fn new(logger: &Logger, size: usize, queue_depth: usize) -> ThreadPool {
         assert!(size > 0);
 
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
 
-        let receiver = Arc::new(Mutex::new(receiver));
+        let context = WorkerContext {
+            logger: logger.clone(),
+            receiver: Arc::new(Mutex::new(receiver)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            panics: Arc::new(AtomicUsize::new(0)),
+        };
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(logger.clone(), id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, context.clone()));
         }
 
         ThreadPool {
-            _workers: workers,
-            sender,
+            workers,
+            sender: Some(sender),
+            queue_depth,
+            context,
+        }
+    }
+
+    /// Jobs waiting for a free worker.
+    pub fn queued(&self) -> usize {
+        self.context.queued.load(Ordering::SeqCst)
+    }
+
+    /// Jobs which panicked since the pool was started.
+    pub fn panics(&self) -> usize {
+        self.context.panics.load(Ordering::SeqCst)
+    }
+
+    /// Replaces workers which stopped after a panic, so the pool keeps its size.
+    fn respawn_dead(&mut self) {
+        for worker in self.workers.iter_mut() {
+            if worker.is_finished() {
+                let id = worker.id;
+                if let Some(thread) = worker.thread.take() {
+                    let _ = thread.join();
+                }
+                self.context
+                    .logger
+                    .info(format!("Respawning worker {id}").as_str());
+                *worker = Worker::new(id, self.context.clone());
+            }
         }
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Whether `execute` would have to wait for room in the queue.
+    pub fn is_full(&self) -> bool {
+        self.queued() >= self.queue_depth
+    }
+
+    /// Queues a job, returns `false` if the queue is full.
+    pub fn execute<F>(&mut self, f: F) -> bool
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.send(job).unwrap();
+        self.respawn_dead();
+        let job = QueuedJob {
+            job: Box::new(f),
+            queued_at: Instant::now(),
+        };
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return false,
+        };
+        self.context.queued.fetch_add(1, Ordering::SeqCst);
+        if sender.try_send(job).is_err() {
+            self.context.queued.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    /// Stops taking jobs and waits up to `grace` for the workers to finish the queued ones.
+    /// Returns the number of workers still busy after that.
+    pub fn shutdown(&mut self, grace: Duration) -> usize {
+        // Workers stop once the queue is empty and closed
+        drop(self.sender.take());
+
+        let deadline = Instant::now() + grace;
+        while self.workers.iter().any(|t| !t.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        for worker in self.workers.iter_mut() {
+            if worker.is_finished() {
+                if let Some(thread) = worker.thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
+        self.workers.retain(|t| t.thread.is_some());
+        self.workers.len()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        if self.sender.is_some() {
+            self.shutdown(DROP_GRACE);
+        }
     }
 }
 
 struct Worker {
-    _id: usize,
-    _thread: thread::JoinHandle<()>,
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(mut logger: Logger, id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
-            while let Ok(job) = receiver.lock().unwrap().recv() {
-                logger.info(format!("Worker {id} got a job; executing.").as_str()); // TODO: Change to logger
-                job();
+    fn new(id: usize, context: WorkerContext) -> Worker {
+        let WorkerContext {
+            mut logger,
+            receiver,
+            queued,
+            panics,
+        } = context;
+        let thread = thread::spawn(move || loop {
+            // The lock is released before the job runs, so other workers can take jobs meanwhile
+            let queued_job = receiver.lock().unwrap().recv();
+            let queued_job = match queued_job {
+                Ok(queued_job) => queued_job,
+                Err(_) => break,
+            };
+            let waiting = queued.fetch_sub(1, Ordering::SeqCst) - 1;
+            logger.info(
+                format!(
+                    "Worker {id} got a job after {:?} in queue ({waiting} waiting); executing.",
+                    queued_job.queued_at.elapsed()
+                )
+                .as_str(),
+            );
+            // A panicking job may leave thread local state broken, so the worker
+            // stops and the pool starts a fresh one in its place
+            if panic::catch_unwind(AssertUnwindSafe(queued_job.job)).is_err() {
+                let count = panics.fetch_add(1, Ordering::SeqCst) + 1;
+                logger.error(
+                    format!("Worker {id} panicked ({count} panics so far); stopping.").as_str(),
+                );
+                break;
             }
         });
 
         Worker {
-            _id: id,
-            _thread: thread,
+            id,
+            thread: Some(thread),
         }
     }
+
+    fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|t| t.is_finished())
+    }
 }